@@ -0,0 +1,264 @@
+//! Admission of clients before they learn anything about the server's memory.
+//!
+//! The server sends a random challenge, the client answers with its name and
+//! an HMAC-SHA256 of the challenge keyed with its secret, and the server
+//! tells it whether it is admitted. Only then are the conn data, with the
+//! rkeys, exchanged. The secret is shared by every client, or looked up by
+//! name in a token file.
+use crate::error::{RdmaKvError, Result};
+use crate::handshake;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+const NONCE_SIZE: usize = 32;
+// keeps a MAC from being mistaken for one of another protocol
+const MAC_CONTEXT: &[u8] = b"rdma-kv client admission";
+// what a rejected client is told, whatever went wrong
+const REJECTED: &str = "authentication failed";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A secret given on the command line, kept out of the printed config.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("the secret is empty".to_string());
+        }
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+impl Secret {
+    fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// What a client needs to be admitted, sent by the server.
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    nonce: [u8; NONCE_SIZE],
+}
+
+/// The client's answer to a `Challenge`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    name: String,
+    mac: [u8; 32],
+}
+
+/// Whether the server admits the client, sent last.
+#[derive(Debug, Serialize, Deserialize)]
+enum Admission {
+    Accepted,
+    Rejected(String),
+}
+
+/// The secrets a server admits clients with.
+#[derive(Debug, Clone)]
+pub enum Keys {
+    /// Every client is admitted.
+    Open,
+    /// The secret every client knows.
+    Shared(Secret),
+    /// The secret of each client, by name.
+    Tokens(HashMap<String, Secret>),
+}
+
+impl Keys {
+    /// The keys of a server, from a token file or a shared secret.
+    pub fn new(secret: Option<&Secret>, tokens: Option<&Path>) -> Result<Self> {
+        match (tokens, secret) {
+            (Some(path), _) => Ok(Keys::Tokens(read_tokens(path)?)),
+            (None, Some(secret)) => Ok(Keys::Shared(secret.clone())),
+            (None, None) => Ok(Keys::Open),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Secret> {
+        match self {
+            Keys::Open => None,
+            Keys::Shared(secret) => Some(secret),
+            Keys::Tokens(tokens) => tokens.get(name),
+        }
+    }
+}
+
+// a token file holds a "<name> <token>" line per client, blank lines and
+// lines starting with # are skipped
+fn read_tokens(path: &Path) -> Result<HashMap<String, Secret>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        RdmaKvError::InvalidInput(format!("reading token file {}: {}", path.display(), e))
+    })?;
+    let mut tokens = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(name), Some(token), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "{}:{}: expected a name and a token",
+                path.display(),
+                n + 1
+            )));
+        };
+        tokens.insert(name.to_string(), Secret(token.to_string()));
+    }
+    if tokens.is_empty() {
+        return Err(RdmaKvError::InvalidInput(format!(
+            "token file {} has no tokens",
+            path.display()
+        )));
+    }
+    Ok(tokens)
+}
+
+/// `N` bytes from the kernel's CSPRNG.
+pub fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| RdmaKvError::resource("reading /dev/urandom", e))?;
+    Ok(bytes)
+}
+
+// what a client named name proves it holds key with, the MAC is verified
+// in constant time
+fn mac(key: &Secret, nonce: &[u8; NONCE_SIZE], name: &str) -> Result<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes())
+        .map_err(|e| RdmaKvError::InvalidInput(format!("secret: {}", e)))?;
+    mac.update(MAC_CONTEXT);
+    mac.update(nonce);
+    mac.update(name.as_bytes());
+    Ok(mac)
+}
+
+fn send<T: Serialize>(stream: &mut impl Write, msg: &T) -> Result<()> {
+    stream
+        .write_all(&handshake::encode(msg)?)
+        .map_err(|e| RdmaKvError::connection("sending admission", e))
+}
+
+/// Challenge the client on `stream`, returns its name once it is admitted.
+/// `peer` names it in the error of a failed attempt.
+pub fn admit(
+    stream: &mut (impl Read + Write),
+    keys: &Keys,
+    peer: impl fmt::Display,
+) -> Result<String> {
+    let nonce = random()?;
+    send(stream, &Challenge { nonce })?;
+    let (_, response): (_, Response) = handshake::read(stream)?;
+    let admitted = match (keys, keys.get(&response.name)) {
+        (Keys::Open, _) => true,
+        (_, Some(key)) => mac(key, &nonce, &response.name)?
+            .verify_slice(&response.mac)
+            .is_ok(),
+        (_, None) => false,
+    };
+    if !admitted {
+        // unknown names and wrong secrets look the same to the client
+        send(stream, &Admission::Rejected(REJECTED.to_string()))?;
+        return Err(RdmaKvError::connection(
+            format!("{} failed to authenticate as {:?}", peer, response.name),
+            io::ErrorKind::PermissionDenied.into(),
+        ));
+    }
+    send(stream, &Admission::Accepted)?;
+    Ok(response.name)
+}
+
+/// Answer the challenge of the server on `stream` as `name`, with `secret`.
+pub fn prove(stream: &mut (impl Read + Write), name: &str, secret: Option<&Secret>) -> Result<()> {
+    let (_, challenge): (_, Challenge) = handshake::read(stream)?;
+    let mac = match secret {
+        Some(secret) => mac(secret, &challenge.nonce, name)?
+            .finalize()
+            .into_bytes()
+            .into(),
+        None => [0; 32],
+    };
+    send(
+        stream,
+        &Response {
+            name: name.to_string(),
+            mac,
+        },
+    )?;
+    let (_, admission): (_, Admission) = handshake::read(stream)?;
+    match admission {
+        Admission::Accepted => Ok(()),
+        Admission::Rejected(reason) => Err(RdmaKvError::connection(
+            format!("server rejected the client: {}", reason),
+            io::ErrorKind::PermissionDenied.into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    // admit a client named name that holds secret, with keys
+    fn run(keys: Keys, name: &str, secret: Option<&str>) -> (Result<String>, Result<()>) {
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        let secret = secret.map(|s| s.parse::<Secret>().unwrap());
+        let name = name.to_string();
+        let client = std::thread::spawn(move || prove(&mut client, &name, secret.as_ref()));
+        let admitted = admit(&mut server, &keys, "peer");
+        (admitted, client.join().unwrap())
+    }
+
+    #[test]
+    fn shared_secret() {
+        let keys = Keys::Shared("s3cret".parse().unwrap());
+        let (admitted, proved) = run(keys.clone(), "a", Some("s3cret"));
+        assert_eq!(admitted.unwrap(), "a");
+        proved.unwrap();
+        let (admitted, proved) = run(keys.clone(), "a", Some("wrong"));
+        assert!(admitted.is_err() && proved.is_err());
+        let (admitted, proved) = run(keys, "a", None);
+        assert!(admitted.is_err() && proved.is_err());
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = [("a", "ta"), ("b", "tb")]
+            .into_iter()
+            .map(|(name, token)| (name.to_string(), token.parse().unwrap()))
+            .collect();
+        let keys = Keys::Tokens(tokens);
+        assert!(run(keys.clone(), "b", Some("tb")).1.is_ok());
+        // the token of another client, and an unknown name
+        assert!(run(keys.clone(), "b", Some("ta")).1.is_err());
+        assert!(run(keys, "c", Some("ta")).1.is_err());
+    }
+
+    #[test]
+    fn open() {
+        assert_eq!(run(Keys::Open, "a", None).0.unwrap(), "a");
+    }
+}
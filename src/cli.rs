@@ -1,9 +1,14 @@
+use crate::auth::Secret;
+use crate::frame::DEFAULT_BUF_SIZE;
+use crate::gid::Gid;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
+use std::{fmt, io::Write, path::PathBuf, str::FromStr, time::Duration};
 
-#[derive(Parser, Debug, Default)]
+#[derive(Parser, Debug, Clone)]
 pub struct RdmaOpt {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
     /// Optional server host. if it is none, the pingpong binary run as server
     pub server: Option<String>,
     /// listen on/connect to port <port> (default 18515)
@@ -12,27 +17,351 @@ pub struct RdmaOpt {
     /// use IB device <dev> (default first device found)
     #[clap(short = 'd', long)]
     pub ib_devname: Option<String>,
-    /// local port gid index
-    #[clap(short = 'g', long, default_value_t = 0)]
-    pub gidx: i32,
+    /// local port gid index, a negative one uses no gid. auto picks the
+    /// RoCE v2 IPv4 gid of the interface the server is reached through, a
+    /// gid such as ::ffff:192.168.1.2 picks the index that holds it
+    #[clap(short = 'g', long, default_value_t = GidIndex::Auto, allow_hyphen_values = true)]
+    pub gidx: GidIndex,
     /// use port <port> of IB device (default 1)
     #[clap(short = 'i', long, default_value_t = 1)]
     pub ib_port: u8,
-    /// client number
-    #[clap(short = 'n', long, default_value_t = 1)]
-    pub client_num: usize,
+    /// size in bytes of each message buffer, the smaller size of the two
+    /// peers is used
+    #[clap(short = 'b', long, default_value_t = DEFAULT_BUF_SIZE)]
+    pub buf_size: usize,
+    /// wait for completions on a completion channel instead of busy polling
+    /// the CQ
+    #[clap(long)]
+    pub comp_channel: bool,
+    /// maximum outstanding work requests on each send and receive queue,
+    /// the CQ is sized to hold all of them
+    #[clap(short = 'q', long, default_value_t = 16)]
+    pub queue_depth: u32,
+    /// buckets of the hash index the server exposes for one-sided gets, 0
+    /// makes every get go through the server
+    #[clap(long, default_value_t = 1024)]
+    pub index_buckets: usize,
+    /// 8 byte counter slots the server exposes for RDMA atomics, 0 makes
+    /// every counter operation go through the server, as does an HCA whose
+    /// atomics are not IBV_ATOMIC_GLOB. A slot serves one counter, it is not
+    /// reused once that is deleted or overwritten
+    #[clap(long, default_value_t = 1024)]
+    pub counter_slots: usize,
+    /// clients the server accepts at the same time, more are turned away
+    /// until one leaves
+    #[clap(short = 'n', long, default_value_t = 64)]
+    pub max_clients: usize,
+    /// run server and client in this process over the in-memory loopback
+    /// transport, no RDMA device is needed
+    #[clap(long)]
+    pub loopback: bool,
+    /// path MTU in bytes, one of 256, 512, 1024, 2048 and 4096 (default the
+    /// smaller active MTU of the two ports)
+    #[clap(long)]
+    pub mtu: Option<u32>,
+    /// service level of the path, 0 to 15
+    #[clap(long, default_value_t = 0)]
+    pub sl: u8,
+    /// hop limit of the packets when a gid is used
+    #[clap(long, default_value_t = 1)]
+    pub hop_limit: u8,
+    /// local ACK timeout, 4.096 us * 2^timeout, 0 to 31 where 0 waits
+    /// forever
+    #[clap(long, default_value_t = 18)]
+    pub timeout: u8,
+    /// retries of a request that was not acked in time, 0 to 7
+    #[clap(long, default_value_t = 6)]
+    pub retry_cnt: u8,
+    /// retries of a request the peer had no receive posted for, 0 to 7
+    /// where 7 retries forever. Requests are written with immediate data,
+    /// which needs a receive of the peer, so the default no longer fails
+    /// them at the first RNR NAK as the 0 used before did
+    #[clap(long, default_value_t = 7)]
+    pub rnr_retry: u8,
+    /// how long the peer waits before retrying a request this side had no
+    /// receive posted for, encoded as in the IB spec, 0 to 31
+    #[clap(long, default_value_t = 18)]
+    pub min_rnr_timer: u8,
+    /// how the conn data is exchanged: over a TCP socket on --tcp-port, or
+    /// by the RDMA connection manager on the same port, which also resolves
+    /// the path and picks the gid
+    #[clap(long, value_enum, default_value_t = ConnManager::Tcp)]
+    pub cm: ConnManager,
+    /// secret a client proves it knows before it is told the server's rkeys:
+    /// the one every client shares, or the token of --name from the
+    /// server's --tokens. A server without either admits every client
+    #[clap(long)]
+    pub secret: Option<Secret>,
+    /// file of the server with a "<name> <token>" line per client, used
+    /// instead of --secret
+    #[clap(long, parse(from_os_str))]
+    pub tokens: Option<PathBuf>,
+    /// name the client authenticates as
+    #[clap(long, default_value = "")]
+    pub name: String,
+    /// how the server stores the values: a hash map, or a B-tree that
+    /// keeps the keys in order
+    #[clap(long, value_enum, default_value_t = EngineKind::Hash)]
+    pub engine: EngineKind,
+    /// write-ahead log of the server, replayed on start and appended to
+    /// with every change. Without it the store only lives in memory. With
+    /// it the counter slots are not used: RDMA atomics could not be logged
+    #[clap(long, parse(from_os_str))]
+    pub wal: Option<PathBuf>,
+    /// when the WAL is flushed to disk: always, before each reply, every
+    /// <n>ms, or never, leaving it to the OS
+    #[clap(long, default_value_t = FsyncPolicy::Always)]
+    pub fsync: FsyncPolicy,
+    /// file the server saves snapshots of the store to, on a save and every
+    /// --snapshot-interval. The WAL is cut back to what came after each one
+    #[clap(long, parse(from_os_str))]
+    pub snapshot: Option<PathBuf>,
+    /// seconds between the snapshots of the server, 0 only saves on a save
+    #[clap(long, default_value_t = 0)]
+    pub snapshot_interval: u64,
+    /// snapshot the server loads on start, before it replays the WAL. A WAL
+    /// cut back after a snapshot needs one, --snapshot when this is missing
+    #[clap(long, parse(from_os_str))]
+    pub restore: Option<PathBuf>,
+    /// port the server answers GET /status on, with the state of the port,
+    /// of every client connection and the last async events. Without it the
+    /// server serves no HTTP. Clients always serve /status on port 3000
+    #[clap(long)]
+    pub status_port: Option<u16>,
+}
+
+/// When the WAL is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record, before the change is acknowledged.
+    Always,
+    /// In the background, at most this long after a record.
+    Every(Duration),
+    /// Whenever the OS writes the page cache back.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => return Ok(FsyncPolicy::Always),
+            "never" => return Ok(FsyncPolicy::Never),
+            _ => {}
+        }
+        match s.strip_suffix("ms").map(str::parse) {
+            Some(Ok(0)) => Err("the fsync interval is 0ms, use always".to_string()),
+            Some(Ok(ms)) => Ok(FsyncPolicy::Every(Duration::from_millis(ms))),
+            _ => Err(format!("{} is neither always, never nor <n>ms", s)),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::Every(interval) => write!(f, "{}ms", interval.as_millis()),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineKind {
+    Hash,
+    Btree,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnManager {
+    Tcp,
+    Rdmacm,
+}
+
+/// The entry of the GID table of the port to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GidIndex {
+    /// Looked up in the GID table once the device is open.
+    Auto,
+    /// A fixed index, negative when no GID is used.
+    Index(i32),
+    /// The index holding this GID.
+    Gid(Gid),
+}
+
+impl FromStr for GidIndex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(GidIndex::Auto);
+        }
+        if let Ok(index) = s.parse() {
+            return Ok(GidIndex::Index(index));
+        }
+        s.parse()
+            .map(GidIndex::Gid)
+            .map_err(|_| format!("{} is neither auto, a gid index nor a gid", s))
+    }
+}
+
+impl fmt::Display for GidIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GidIndex::Auto => write!(f, "auto"),
+            GidIndex::Index(index) => write!(f, "{}", index),
+            GidIndex::Gid(gid) => write!(f, "{}", gid),
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// list the RDMA devices, their ports and GID tables
+    Devices,
+}
+
+// the defaults of the command line, not those of the field types
+impl Default for RdmaOpt {
+    fn default() -> Self {
+        RdmaOpt::parse_from([env!("CARGO_PKG_NAME")])
+    }
 }
 
 #[derive(Debug, Parser, Serialize, Deserialize)]
 pub enum KeyValueOpt {
     /// get key value
     Get { key: String },
-    /// set key value
-    Set { key: String, value: String },
+    /// set key value, it expires after ttl seconds when there is one
+    Set {
+        key: String,
+        value: String,
+        #[clap(long)]
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
     /// delete key value
     Delete { key: String },
+    /// add 1 to the integer value of key, returns the new value
+    Incr { key: String },
+    /// subtract 1 from the integer value of key, returns the new value
+    Decr { key: String },
+    /// set key to new if it holds expected, returns the value it held
+    CompareAndSwap {
+        key: String,
+        #[clap(allow_hyphen_values = true)]
+        expected: i64,
+        #[clap(allow_hyphen_values = true)]
+        new: i64,
+    },
+    /// let key expire in seconds, returns 1, or 0 when it does not exist
+    Expire { key: String, seconds: u64 },
+    /// keep key from expiring, returns 1, or 0 when it was not going to
+    Persist { key: String },
+    /// seconds until key expires, -1 when it does not and -2 when it does
+    /// not exist
+    Ttl { key: String },
+    /// list the keys from start on, up to end, in order
+    Scan {
+        #[clap(long)]
+        #[serde(default)]
+        start: Option<String>,
+        #[clap(long)]
+        #[serde(default)]
+        end: Option<String>,
+        /// most entries in the page
+        #[clap(long, default_value_t = DEFAULT_PAGE_LIMIT)]
+        #[serde(default = "default_page_limit")]
+        limit: usize,
+        /// the cursor of the previous page, the page starts after it
+        #[clap(long)]
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    /// list the keys starting with prefix, in order
+    Prefix {
+        prefix: String,
+        /// most entries in the page
+        #[clap(long, default_value_t = DEFAULT_PAGE_LIMIT)]
+        #[serde(default = "default_page_limit")]
+        limit: usize,
+        /// the cursor of the previous page, the page starts after it
+        #[clap(long)]
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    /// save a snapshot of the store on the server, in the background
+    Save,
+    /// The next message of the page being streamed, sent by the client
+    /// itself.
+    #[clap(skip)]
+    More,
+}
+
+impl KeyValueOpt {
+    /// The key the operation is about, None for those about a range.
+    pub fn key(&self) -> Option<&str> {
+        let key = match self {
+            KeyValueOpt::Get { key }
+            | KeyValueOpt::Set { key, .. }
+            | KeyValueOpt::Delete { key }
+            | KeyValueOpt::Incr { key }
+            | KeyValueOpt::Decr { key }
+            | KeyValueOpt::CompareAndSwap { key, .. }
+            | KeyValueOpt::Expire { key, .. }
+            | KeyValueOpt::Persist { key }
+            | KeyValueOpt::Ttl { key } => key,
+            KeyValueOpt::Scan { .. }
+            | KeyValueOpt::Prefix { .. }
+            | KeyValueOpt::Save
+            | KeyValueOpt::More => return None,
+        };
+        Some(key)
+    }
+
+    /// Whether the reply is a `Page`, streamed in `PageChunk`s.
+    pub fn is_scan(&self) -> bool {
+        matches!(self, KeyValueOpt::Scan { .. } | KeyValueOpt::Prefix { .. })
+    }
+}
+
+/// Entries in a page when a scan does not say.
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+
+fn default_page_limit() -> usize {
+    DEFAULT_PAGE_LIMIT
+}
+
+/// What a `Scan` or a `Prefix` returns.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Page {
+    pub entries: Vec<(String, String)>,
+    /// Where the next page starts, None on the last one.
+    pub cursor: Option<String>,
+}
+
+/// One message of a `Page`, the client asks for the next one with `More`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PageChunk {
+    pub entries: Vec<(String, String)>,
+    /// The cursor of the page, on its last chunk.
+    pub cursor: Option<String>,
+    pub more: bool,
 }
 
+/// What the server answers to a `Scan`, a `Prefix` or a `More`. An error
+/// looks the same as that of a `KvReply`.
+pub type PageReply = Result<PageChunk, String>;
+
+/// What the server answers to every `KeyValueOpt`: the value of a `Get`, or
+/// why the request failed.
+pub type KvReply = Result<Option<String>, String>;
+
+/// The value a `Get` of a missing key answers.
+pub const KEY_NOT_FOUND: &str = "key not found";
+
 pub fn client_opt() -> Result<KeyValueOpt, String> {
     loop {
         write!(std::io::stdout(), "$ ").map_err(|e| e.to_string())?;
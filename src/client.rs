@@ -0,0 +1,343 @@
+use crate::cli::{KeyValueOpt, KvReply, Page, PageReply, RdmaOpt, KEY_NOT_FOUND};
+use crate::error::{RdmaKvError, Result};
+use crate::events::TransportStatus;
+use crate::index::{self, Lookup};
+use crate::transport::{CounterOp, Transport};
+use rdma_sys::ibv_wr_opcode;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+// reads of the index that may find a slot being written before the
+// operation is sent to the server
+const INDEX_READ_RETRIES: usize = 16;
+
+// attempts to bring a broken connection back, the delay before each one
+// doubles from the first up to the max
+const RECONNECT_ATTEMPTS: u32 = 8;
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+// what the index lets an operation do without the server's CPU
+enum Shortcut {
+    Reply(String),
+    Atomic(usize, CounterOp),
+    AskServer,
+}
+
+impl Shortcut {
+    fn new(kv_opt: &KeyValueOpt, lookup: Lookup) -> Self {
+        let op = match *kv_opt {
+            KeyValueOpt::Get { .. } => CounterOp::Read,
+            KeyValueOpt::Incr { .. } => CounterOp::FetchAdd(1),
+            KeyValueOpt::Decr { .. } => CounterOp::FetchAdd(-1i64 as u64),
+            KeyValueOpt::CompareAndSwap { expected, new, .. } => CounterOp::CompareSwap {
+                compare: expected as u64,
+                swap: new as u64,
+            },
+            _ => return Shortcut::AskServer,
+        };
+        match lookup {
+            Lookup::Counter(slot) => Shortcut::Atomic(slot, op),
+            Lookup::Found(value) if op == CounterOp::Read => Shortcut::Reply(value),
+            Lookup::Absent if op == CounterOp::Read => Shortcut::Reply(KEY_NOT_FOUND.to_string()),
+            _ => Shortcut::AskServer,
+        }
+    }
+}
+
+// the reply to kv_opt from the value its counter held before the atomic
+fn counter_reply(kv_opt: &KeyValueOpt, old: u64) -> String {
+    let old = old as i64;
+    match kv_opt {
+        KeyValueOpt::Incr { .. } => old.wrapping_add(1),
+        KeyValueOpt::Decr { .. } => old.wrapping_sub(1),
+        _ => old,
+    }
+    .to_string()
+}
+
+/// Issues KV operations to a `KvServer` through connection 0 of a `Transport`.
+///
+/// When an operation breaks the connection it fails, and the connection is
+/// brought back with exponential backoff for the next one. Operations are
+/// never retried, the server may have applied them already.
+pub struct KvClient<T: Transport> {
+    transport: T,
+    // what the last successful connect used, reconnects use it again
+    config: Option<RdmaOpt>,
+}
+
+impl<T: Transport> KvClient<T> {
+    pub fn new(transport: T) -> Self {
+        KvClient {
+            transport,
+            config: None,
+        }
+    }
+
+    pub fn connect(&mut self, config: &RdmaOpt) -> Result<()> {
+        self.transport.connect(config)?;
+        self.config = Some(config.clone());
+        Ok(())
+    }
+
+    /// What the last successful connect used.
+    pub fn config(&self) -> Option<&RdmaOpt> {
+        self.config.as_ref()
+    }
+
+    /// Health of the connection to the server and the events behind it.
+    pub fn status(&self) -> TransportStatus {
+        self.transport.status()
+    }
+
+    // log how reconnect attempt went, true once it succeeded
+    fn reconnected(attempt: u32, res: &Result<()>) -> bool {
+        match res {
+            Ok(()) => info!("Reconnected to the server after {} attempts", attempt + 1),
+            Err(e) => warn!("Reconnect attempt {} failed: {}", attempt + 1, e),
+        }
+        res.is_ok()
+    }
+
+    // bring the connection back if err broke it
+    fn recover(&mut self, err: &RdmaKvError) {
+        if !err.breaks_connection() {
+            return;
+        }
+        warn!("Connection to the server broke: {}", err);
+        let Some(config) = &self.config else {
+            return;
+        };
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            std::thread::sleep(reconnect_delay(attempt));
+            if Self::reconnected(attempt, &self.transport.reconnect(config)) {
+                return;
+            }
+        }
+        error!("Giving up reconnecting, the next operation tries again");
+    }
+
+    // the reconnects run off the runtime, see Transport::reconnect_async
+    async fn recover_async(&mut self, err: &RdmaKvError) {
+        if !err.breaks_connection() {
+            return;
+        }
+        warn!("Connection to the server broke: {}", err);
+        let Some(config) = self.config.clone() else {
+            return;
+        };
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            tokio::time::sleep(reconnect_delay(attempt)).await;
+            let res = self.transport.reconnect_async(&config).await;
+            if Self::reconnected(attempt, &res) {
+                return;
+            }
+        }
+        error!("Giving up reconnecting, the next operation tries again");
+    }
+
+    // the slots of the server's index `key` may live in, None when it has
+    // to be asked
+    fn index_window(&self, key: &str) -> Option<(usize, usize)> {
+        let buckets = index::buckets(self.transport.remote_index_size(0));
+        if buckets == 0 {
+            return None;
+        }
+        let (offset, len) = index::window(key, buckets);
+        (len <= self.transport.buf_size(0)).then_some((offset, len))
+    }
+
+    // look key up in the window just read into buffer 0
+    fn window_lookup(&self, key: &str, len: usize) -> Lookup {
+        let lookup = index::lookup(&self.transport.read_buf(0)[..len], key);
+        if lookup == Lookup::Torn {
+            debug!("Torn read of the index for {}", key);
+        }
+        lookup
+    }
+
+    // what the server's index says about key, read with RDMA reads alone
+    fn read_index(&mut self, key: &str) -> Result<Lookup> {
+        if let Some((offset, len)) = self.index_window(key) {
+            for _ in 0..INDEX_READ_RETRIES {
+                let wr_id = self.transport.post_read_index(0, offset, len)?;
+                self.transport.wait_for(&mut vec![wr_id])?;
+                match self.window_lookup(key, len) {
+                    Lookup::Torn => {}
+                    lookup => return Ok(lookup),
+                }
+            }
+            warn!("Index reads for {} kept being torn, asking the server", key);
+        }
+        Ok(Lookup::Unindexed)
+    }
+
+    async fn read_index_async(&mut self, key: &str) -> Result<Lookup>
+    where
+        T: Sync,
+    {
+        if let Some((offset, len)) = self.index_window(key) {
+            for _ in 0..INDEX_READ_RETRIES {
+                let wr_id = self.transport.post_read_index(0, offset, len)?;
+                self.transport.wait_for_async(&mut vec![wr_id]).await?;
+                match self.window_lookup(key, len) {
+                    Lookup::Torn => {}
+                    lookup => return Ok(lookup),
+                }
+            }
+            warn!("Index reads for {} kept being torn, asking the server", key);
+        }
+        Ok(Lookup::Unindexed)
+    }
+
+    // write the request and post it, returns the wr_ids to wait for
+    fn post_request(&mut self, kv_opt: &KeyValueOpt) -> Result<Vec<u64>> {
+        let send_str = serde_json::to_vec(kv_opt)?;
+        let len = self.transport.write_msg(&send_str, 0)?;
+
+        // every request is answered, the reply must find a receive already
+        // posted. Waiting for it also keeps the next request from
+        // overwriting this one before the server handled it
+        let mut wr_ids = vec![self.transport.post_receive(0)?];
+        wr_ids.push(
+            self.transport
+                .post_send(ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM, 0, len)?,
+        );
+        Ok(wr_ids)
+    }
+
+    fn read_reply(&self) -> Result<Option<String>> {
+        let reply: KvReply = serde_json::from_slice(&self.transport.read_msg(0)?)?;
+        reply.map_err(RdmaKvError::Store)
+    }
+
+    // add the chunk of a page just received to page, returns whether the
+    // server has more of it
+    fn read_chunk(&self, page: &mut Page) -> Result<bool> {
+        let reply: PageReply = serde_json::from_slice(&self.transport.read_msg(0)?)?;
+        let chunk = reply.map_err(RdmaKvError::Store)?;
+        page.entries.extend(chunk.entries);
+        page.cursor = chunk.cursor;
+        Ok(chunk.more)
+    }
+
+    // what to look up in the index before kv_opt is run
+    fn lookup_key(kv_opt: &KeyValueOpt) -> Result<Option<&str>> {
+        match kv_opt {
+            KeyValueOpt::Set { .. }
+            | KeyValueOpt::Delete { .. }
+            | KeyValueOpt::Expire { .. }
+            | KeyValueOpt::Persist { .. }
+            | KeyValueOpt::Ttl { .. } => Ok(None),
+            KeyValueOpt::More => Err(RdmaKvError::InvalidInput(
+                "More is only sent while a page is received".to_string(),
+            )),
+            _ => Ok(kv_opt.key()),
+        }
+    }
+
+    /// Run one operation, a `Get` returns the value of the key, counter
+    /// operations the value they produced.
+    ///
+    /// Gets of keys in the server's index are answered with RDMA reads, and
+    /// counter operations on keys the index knows as counters with RDMA
+    /// atomics. Everything else goes through the server. A `Scan` or a
+    /// `Prefix` returns the `Page` as JSON, received in as many messages as
+    /// the server needs.
+    pub fn execute(&mut self, kv_opt: &KeyValueOpt) -> Result<Option<String>> {
+        let res = self.execute_once(kv_opt);
+        if let Err(e) = &res {
+            self.recover(e);
+        }
+        res
+    }
+
+    fn execute_once(&mut self, kv_opt: &KeyValueOpt) -> Result<Option<String>> {
+        let lookup = match Self::lookup_key(kv_opt)? {
+            Some(key) => self.read_index(key)?,
+            None => Lookup::Unindexed,
+        };
+        match Shortcut::new(kv_opt, lookup) {
+            Shortcut::Reply(value) => Ok(Some(value)),
+            Shortcut::Atomic(slot, op) => {
+                let wr_id = self.transport.post_counter_op(0, slot, op)?;
+                self.transport.wait_for(&mut vec![wr_id])?;
+                let old = self.transport.read_counter_result(0);
+                Ok(Some(counter_reply(kv_opt, old)))
+            }
+            Shortcut::AskServer if kv_opt.is_scan() => {
+                let mut page = Page::default();
+                let mut request = kv_opt;
+                loop {
+                    let mut wr_ids = self.post_request(request)?;
+                    self.transport.wait_for(&mut wr_ids)?;
+                    if !self.read_chunk(&mut page)? {
+                        return Ok(Some(serde_json::to_string(&page)?));
+                    }
+                    request = &KeyValueOpt::More;
+                }
+            }
+            Shortcut::AskServer => {
+                let mut wr_ids = self.post_request(kv_opt)?;
+                self.transport.wait_for(&mut wr_ids)?;
+                self.read_reply()
+            }
+        }
+    }
+
+    /// Same as `execute`, but waits for completions without blocking the
+    /// runtime.
+    pub async fn execute_async(&mut self, kv_opt: &KeyValueOpt) -> Result<Option<String>>
+    where
+        T: Sync,
+    {
+        let res = self.execute_once_async(kv_opt).await;
+        if let Err(e) = &res {
+            self.recover_async(e).await;
+        }
+        res
+    }
+
+    async fn execute_once_async(&mut self, kv_opt: &KeyValueOpt) -> Result<Option<String>>
+    where
+        T: Sync,
+    {
+        let lookup = match Self::lookup_key(kv_opt)? {
+            Some(key) => self.read_index_async(key).await?,
+            None => Lookup::Unindexed,
+        };
+        match Shortcut::new(kv_opt, lookup) {
+            Shortcut::Reply(value) => Ok(Some(value)),
+            Shortcut::Atomic(slot, op) => {
+                let wr_id = self.transport.post_counter_op(0, slot, op)?;
+                self.transport.wait_for_async(&mut vec![wr_id]).await?;
+                let old = self.transport.read_counter_result(0);
+                Ok(Some(counter_reply(kv_opt, old)))
+            }
+            Shortcut::AskServer if kv_opt.is_scan() => {
+                let mut page = Page::default();
+                let mut request = kv_opt;
+                loop {
+                    let mut wr_ids = self.post_request(request)?;
+                    self.transport.wait_for_async(&mut wr_ids).await?;
+                    if !self.read_chunk(&mut page)? {
+                        return Ok(Some(serde_json::to_string(&page)?));
+                    }
+                    request = &KeyValueOpt::More;
+                }
+            }
+            Shortcut::AskServer => {
+                let mut wr_ids = self.post_request(kv_opt)?;
+                self.transport.wait_for_async(&mut wr_ids).await?;
+                self.read_reply()
+            }
+        }
+    }
+}
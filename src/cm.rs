@@ -0,0 +1,330 @@
+//! Owned wrappers around the librdmacm objects, see `verbs` for the ibverbs
+//! ones.
+//!
+//! The ids never get a QP of their own: the QPs are created on the device
+//! `RdmaContext` opened, and moved through their states with the attributes
+//! `rdma_init_qp_attr` resolves. Both ways of connecting share the QP code
+//! that way, the CM only resolves the path and carries the conn data.
+use crate::error::{RdmaKvError, Result};
+use rdma_sys::*;
+use std::{ffi::CStr, io, net::SocketAddr, os::raw::c_void, sync::Arc};
+use tracing::{debug, warn};
+
+// the private data a reject carries on IB and RoCE
+const REJECT_PRIVATE_DATA: usize = 148;
+
+/// Name of a CM event type, such as `RDMA_CM_EVENT_ESTABLISHED`.
+pub fn event_str(event: rdma_cm_event_type::Type) -> String {
+    let name = unsafe { rdma_event_str(event) };
+    if name.is_null() {
+        return format!("{}", event);
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// the sockaddr librdmacm takes for addr
+fn sockaddr(addr: SocketAddr) -> libc::sockaddr_storage {
+    let mut storage = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as _;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as _;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+        }
+    }
+    storage
+}
+
+/// The channel the events of a listening id, and of the ids of its connect
+/// requests, arrive on.
+pub struct EventChannel {
+    channel: *mut rdma_event_channel,
+}
+
+unsafe impl Send for EventChannel {}
+unsafe impl Sync for EventChannel {}
+
+/// A CM event, copied out before it was acked.
+pub struct CmEvent {
+    pub event: rdma_cm_event_type::Type,
+    /// An errno, or the reason of a reject.
+    pub status: i32,
+    /// What `CmId::set_context` stored in the id the event is about.
+    pub context: Option<usize>,
+    /// What the peer sent with its connect request, accept or reject.
+    pub private_data: Vec<u8>,
+    /// The id of a connect request, it is accepted or rejected through it.
+    pub request: Option<CmId>,
+}
+
+impl EventChannel {
+    pub fn new() -> Result<Arc<Self>> {
+        let channel = unsafe { rdma_create_event_channel() };
+        if channel.is_null() {
+            return Err(RdmaKvError::resource(
+                "creating CM event channel",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(Arc::new(EventChannel { channel }))
+    }
+
+    /// Block until the next event.
+    pub fn get_event(self: &Arc<Self>) -> Result<CmEvent> {
+        let mut event: *mut rdma_cm_event = std::ptr::null_mut();
+        if unsafe { rdma_get_cm_event(self.channel, &mut event) } != 0 {
+            return Err(RdmaKvError::connection(
+                "reading CM event",
+                io::Error::last_os_error(),
+            ));
+        }
+        // an id is only destroyed once its events are acked, so it can be
+        // read until then
+        let copy = unsafe {
+            let event = &*event;
+            let conn = event.param.conn;
+            let private_data = if conn.private_data.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(
+                    conn.private_data as *const u8,
+                    conn.private_data_len as usize,
+                )
+                .to_vec()
+            };
+            CmEvent {
+                event: event.event,
+                status: event.status,
+                context: ((*event.id).context as usize).checked_sub(1),
+                private_data,
+                request: (event.event == rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_REQUEST).then(
+                    || CmId {
+                        id: event.id,
+                        _channel: self.clone(),
+                    },
+                ),
+            }
+        };
+        unsafe { rdma_ack_cm_event(event) };
+        debug!("Got CM event {}", event_str(copy.event));
+        Ok(copy)
+    }
+}
+
+impl Drop for EventChannel {
+    fn drop(&mut self) {
+        unsafe { rdma_destroy_event_channel(self.channel) };
+    }
+}
+
+/// What a connect or an accept tells the peer.
+pub struct ConnParams<'a> {
+    pub private_data: &'a [u8],
+    /// The QP the peer's QP is connected to.
+    pub qp_num: u32,
+    pub retry_count: u8,
+    pub rnr_retry_count: u8,
+}
+
+impl ConnParams<'_> {
+    fn ffi(&self) -> Result<rdma_conn_param> {
+        let mut param = unsafe { std::mem::zeroed::<rdma_conn_param>() };
+        param.private_data = self.private_data.as_ptr() as *const c_void;
+        param.private_data_len = self.private_data.len().try_into().map_err(|_| {
+            RdmaKvError::Protocol(format!(
+                "{} bytes of private data are too many",
+                self.private_data.len()
+            ))
+        })?;
+        // one RDMA read or atomic in flight each way, as without the CM
+        param.responder_resources = 1;
+        param.initiator_depth = 1;
+        param.retry_count = self.retry_count;
+        param.rnr_retry_count = self.rnr_retry_count;
+        param.qp_num = self.qp_num;
+        Ok(param)
+    }
+}
+
+/// An RC connection of the CM, or a listener for them.
+pub struct CmId {
+    id: *mut rdma_cm_id,
+    _channel: Arc<EventChannel>,
+}
+
+unsafe impl Send for CmId {}
+unsafe impl Sync for CmId {}
+
+// the error of a librdmacm call, which returns -1 and sets errno
+fn check(ret: i32, context: &str) -> Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(RdmaKvError::connection(context, io::Error::last_os_error()))
+    }
+}
+
+impl CmId {
+    pub fn new(channel: &Arc<EventChannel>) -> Result<Self> {
+        let mut id: *mut rdma_cm_id = std::ptr::null_mut();
+        let ret = unsafe {
+            rdma_create_id(
+                channel.channel,
+                &mut id,
+                std::ptr::null_mut(),
+                rdma_port_space::RDMA_PS_TCP,
+            )
+        };
+        if ret != 0 {
+            return Err(RdmaKvError::resource(
+                "creating CM id",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(CmId {
+            id,
+            _channel: channel.clone(),
+        })
+    }
+
+    /// Stored in the id, events about it carry it.
+    pub fn set_context(&self, context: usize) {
+        // 0 is the null pointer of an id that has none
+        unsafe { (*self.id).context = (context + 1) as *mut c_void };
+    }
+
+    /// Name of the device the id is bound to, once its address is resolved
+    /// or for a connect request.
+    pub fn device_name(&self) -> Option<String> {
+        let verbs = unsafe { (*self.id).verbs };
+        if verbs.is_null() {
+            return None;
+        }
+        let name = unsafe { ibv_get_device_name((*verbs).device) };
+        (!name.is_null()).then(|| {
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    pub fn port_num(&self) -> u8 {
+        unsafe { (*self.id).port_num }
+    }
+
+    /// Accept connect requests on `addr`.
+    pub fn listen(&self, addr: SocketAddr, backlog: i32) -> Result<()> {
+        let mut addr_storage = sockaddr(addr);
+        check(
+            unsafe { rdma_bind_addr(self.id, &mut addr_storage as *mut _ as *mut _) },
+            &format!("binding {}", addr),
+        )?;
+        check(
+            unsafe { rdma_listen(self.id, backlog) },
+            &format!("listening on {}", addr),
+        )
+    }
+
+    /// Start resolving `addr`, `RDMA_CM_EVENT_ADDR_RESOLVED` follows.
+    pub fn resolve_addr(&self, addr: SocketAddr, timeout_ms: i32) -> Result<()> {
+        let mut addr_storage = sockaddr(addr);
+        check(
+            unsafe {
+                rdma_resolve_addr(
+                    self.id,
+                    std::ptr::null_mut(),
+                    &mut addr_storage as *mut _ as *mut _,
+                    timeout_ms,
+                )
+            },
+            &format!("resolving {}", addr),
+        )
+    }
+
+    /// Start resolving the route, `RDMA_CM_EVENT_ROUTE_RESOLVED` follows.
+    pub fn resolve_route(&self, timeout_ms: i32) -> Result<()> {
+        check(
+            unsafe { rdma_resolve_route(self.id, timeout_ms) },
+            "resolving route",
+        )
+    }
+
+    /// The attributes, and their mask, that move a QP of this connection to
+    /// `state`.
+    pub fn init_qp_attr(
+        &self,
+        state: ibv_qp_state::Type,
+    ) -> Result<(ibv_qp_attr, ibv_qp_attr_mask)> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        qp_attr.qp_state = state;
+        let mut mask = 0;
+        check(
+            unsafe { rdma_init_qp_attr(self.id, &mut qp_attr, &mut mask) },
+            "getting QP attributes",
+        )?;
+        Ok((qp_attr, ibv_qp_attr_mask(mask as _)))
+    }
+
+    /// Send the connect request, `RDMA_CM_EVENT_CONNECT_RESPONSE` follows.
+    pub fn connect(&self, params: &ConnParams) -> Result<()> {
+        let mut param = params.ffi()?;
+        check(unsafe { rdma_connect(self.id, &mut param) }, "connecting")
+    }
+
+    /// Complete a connect once the QP is in RTS.
+    pub fn establish(&self) -> Result<()> {
+        check(
+            unsafe { rdma_establish(self.id) },
+            "establishing connection",
+        )
+    }
+
+    /// Accept a connect request, the QP must be in RTS already.
+    pub fn accept(&self, params: &ConnParams) -> Result<()> {
+        let mut param = params.ffi()?;
+        check(unsafe { rdma_accept(self.id, &mut param) }, "accepting")
+    }
+
+    /// Reject a connect request, the peer gets `reason` as private data.
+    pub fn reject(&self, reason: &str) {
+        let reason = &reason.as_bytes()[..reason.len().min(REJECT_PRIVATE_DATA)];
+        let ret = unsafe {
+            rdma_reject(
+                self.id,
+                reason.as_ptr() as *const c_void,
+                reason.len() as u8,
+            )
+        };
+        if ret != 0 {
+            warn!(
+                "Rejecting connect request failed: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Tell the peer the connection is gone, it gets
+    /// `RDMA_CM_EVENT_DISCONNECTED`.
+    pub fn disconnect(&self) {
+        // fails when the peer disconnected first, which is fine
+        unsafe { rdma_disconnect(self.id) };
+    }
+}
+
+impl Drop for CmId {
+    fn drop(&mut self) {
+        if unsafe { rdma_destroy_id(self.id) } != 0 {
+            warn!("destroying CM id failed: {}", io::Error::last_os_error());
+        }
+    }
+}
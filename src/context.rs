@@ -1,276 +1,446 @@
-use crate::cli::{KeyValueOpt, RdmaOpt};
+use crate::auth::{self, Keys};
+use crate::cli::{ConnManager, GidIndex, RdmaOpt};
+use crate::cm::{self, CmEvent, CmId, ConnParams, EventChannel};
+use crate::devices;
+use crate::error::{RdmaKvError, Result};
+use crate::events::{ConnectionStatus, EventLog, RdmaEvent, TransportStatus};
+use crate::frame::FRAME_HEADER_SIZE;
 use crate::gid::Gid;
+use crate::handshake::{
+    self, Capabilities, ALL_FEATURES, FEATURE_COUNTERS, FEATURE_INDEX, OP_ATOMIC, OP_RDMA_READ,
+    OP_WRITE_WITH_IMM,
+};
+use crate::index;
+use crate::transport::{make_wr_id, wr_id_qp, Completion, CounterOp, Transport};
+use crate::verbs::{
+    mtu_bytes, CompChannel, CompletionQueue, DeviceContext, MemoryRegion, ProtectionDomain,
+    QueuePair, RegionBuf,
+};
 use rdma_sys::*;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
 use std::{
     collections::HashMap,
-    ffi::CStr,
+    future::Future,
     io::{self, Read, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::fd::RawFd,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
 };
+use tokio::{io::unix::AsyncFd, sync::OnceCell};
 use tracing::{debug, error, info, warn};
 
-const BUFFER_SIZE: usize = 100; //1MB
+// how long a client may take to send its conn data
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// clients admitted at once, each on its own thread
+const MAX_HANDSHAKES: usize = 64;
+// how often the event monitor checks whether the device is still used, in ms
+const MONITOR_INTERVAL: i32 = 1000;
+// how long the CM may take to resolve the address and the route, in ms
+const CM_RESOLVE_TIMEOUT: i32 = 2000;
+// the private data a connect request and an accept carry on IB and RoCE
+const CM_CONNECT_PRIVATE_DATA: usize = 56;
+const CM_ACCEPT_PRIVATE_DATA: usize = 196;
 
-// connection manager data
-// structure to exchange data which is needed to connect the QPs
-#[derive(Default, Debug, Serialize, Deserialize, Clone)]
-struct CmConData {
-    addr: u64,   /* Buffer address */
-    rkey: u32,   /* Remote key */
+// where the QP of a peer is, the CM resolves it by itself
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy)]
+struct QpPath {
     qp_num: u32, /* QP number */
+    psn: u32,    /* First PSN the QP sends */
     lid: u16,    /* LID of the IB port */
     gid: Gid,    /* gid */
 }
 
-#[derive(Clone)]
-pub struct RdmaContext {
-    port_attr: ibv_port_attr,
-    remote_props: CmConData,
-    ib_ctx: *mut ibv_context,
-    pd: *mut ibv_pd,
-    cq: *mut ibv_cq,
-    qps: Vec<*mut ibv_qp>,
-    mrs: Vec<*mut ibv_mr>,
-    bufs: Vec<Vec<u8>>,
-    kv_store: Option<HashMap<String, String>>,
+// connection manager data
+// structure to exchange data which is needed to connect the QPs, it is
+// sent as a handshake message, see the handshake module
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+struct CmConData {
+    caps: Capabilities,   /* What this side supports */
+    addr: u64,            /* Buffer address */
+    rkey: u32,            /* Remote key */
+    index_addr: u64,      /* Index region address */
+    index_rkey: u32,      /* Remote key of the index region */
+    index_size: u64,      /* Size of the index region, 0 if there is none */
+    counters_addr: u64,   /* Counter slots address */
+    counters_rkey: u32,   /* Remote key of the counter slots */
+    counter_slots: u64,   /* Number of counter slots, 0 if there are none */
+    path: Option<QpPath>, /* Path to the QP, None through the CM */
 }
 
-unsafe impl Send for RdmaContext {}
-unsafe impl Sync for RdmaContext {}
+// the ibv_mtu of an MTU given in bytes
+fn mtu_from_bytes(bytes: u32) -> Option<ibv_mtu::Type> {
+    match bytes {
+        256 => Some(ibv_mtu::IBV_MTU_256),
+        512 => Some(ibv_mtu::IBV_MTU_512),
+        1024 => Some(ibv_mtu::IBV_MTU_1024),
+        2048 => Some(ibv_mtu::IBV_MTU_2048),
+        4096 => Some(ibv_mtu::IBV_MTU_4096),
+        _ => None,
+    }
+}
 
-impl RdmaContext {
-    pub fn create(config: &RdmaOpt) -> io::Result<Self> {
-        // get dev
-        let mut num_devs: i32 = 0;
-        let dev_list_ptr = unsafe { ibv_get_device_list(&mut num_devs) };
-        let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as _) };
-        let dev = if let Some(dev_name_inner) = &config.ib_devname {
-            dev_list
-                .iter()
-                .find(|iter_dev| -> bool {
-                    let name = unsafe { ibv_get_device_name(**iter_dev) };
-                    assert!(!name.is_null());
-                    let name = unsafe { CStr::from_ptr(name) }.to_str();
-                    if name.is_err() {
-                        warn!("Device name {:?} is not valid", name);
-                        return false;
-                    }
+// reject the options the IB spec has no encoding for
+fn check_config(config: &RdmaOpt) -> Result<()> {
+    if config.buf_size <= FRAME_HEADER_SIZE || config.buf_size > u32::MAX as usize {
+        return Err(RdmaKvError::InvalidInput(format!(
+            "invalid buffer size {}",
+            config.buf_size
+        )));
+    }
+    if config.queue_depth == 0 {
+        return Err(RdmaKvError::InvalidInput(
+            "queue depth must be at least 1".to_string(),
+        ));
+    }
+    if let Some(mtu) = config.mtu.filter(|&mtu| mtu_from_bytes(mtu).is_none()) {
+        return Err(RdmaKvError::InvalidInput(format!("invalid MTU {}", mtu)));
+    }
+    let limits = [
+        ("service level", config.sl, 15),
+        ("timeout", config.timeout, 31),
+        ("retry count", config.retry_cnt, 7),
+        ("RNR retry count", config.rnr_retry, 7),
+        ("min RNR timer", config.min_rnr_timer, 31),
+    ];
+    for (what, value, max) in limits {
+        if value > max {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "{} {} is above {}",
+                what, value, max
+            )));
+        }
+    }
+    if config.cm == ConnManager::Rdmacm && (config.secret.is_some() || config.tokens.is_some()) {
+        return Err(RdmaKvError::InvalidInput(
+            "clients are only authenticated with --cm tcp, CM private data has no room for \
+             the challenge"
+                .to_string(),
+        ));
+    }
+    if !matches!(config.gidx, GidIndex::Index(index) if index < 0) && config.hop_limit == 0 {
+        return Err(RdmaKvError::InvalidInput(
+            "hop limit must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-                    // We've checked the name is not error
-                    #[allow(clippy::unwrap_used)]
-                    let name = name.unwrap();
-                    dev_name_inner.eq(name)
-                })
-                .ok_or(io::ErrorKind::NotFound)?
-        } else {
-            dev_list.first().ok_or(io::ErrorKind::NotFound)?
-        };
-        // open the dev
-        let ib_ctx = unsafe { ibv_open_device(*dev) };
-        assert!(!ib_ctx.is_null());
-        // free the list ptr
-        unsafe { ibv_free_device_list(dev_list_ptr) };
-        let mut port_attr = unsafe { std::mem::zeroed() };
-        // query port properties
-        unsafe {
-            ___ibv_query_port(ib_ctx, config.ib_port, &mut port_attr);
-        }
-        // alloc pd
-        let pd = unsafe { ibv_alloc_pd(ib_ctx) };
-        assert!(!pd.is_null());
-        // create cq
-        let cq_size = 1;
-        let cq = unsafe {
-            ibv_create_cq(
-                ib_ctx,
-                cq_size,
-                std::ptr::null_mut(),
-                std::ptr::null_mut(),
-                0,
-            )
-        };
-        assert!(!cq.is_null());
-        let client_num = if config.server.is_some() {
-            1
-        } else {
-            config.client_num
-        };
-        let mut qps: Vec<*mut ibv_qp> = Vec::new();
-        let mut bufs: Vec<Vec<u8>> = Vec::new();
-        let mut mrs: Vec<*mut ibv_mr> = Vec::new();
-        for _ in 0..client_num {
-            // create buffer
-            let mut buf = vec![0; BUFFER_SIZE];
-            debug!("Local Buffer addr: {:p}", buf.as_ptr());
-            // register the memory buffer
-            let mr_access_flags = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
-                | ibv_access_flags::IBV_ACCESS_REMOTE_READ
-                | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE;
-            let mr = unsafe {
-                ibv_reg_mr(
-                    pd,
-                    buf.as_mut_ptr() as *mut _,
-                    BUFFER_SIZE,
-                    mr_access_flags.0 as i32,
-                )
-            };
-            debug!("MR registered with addr={:p}", buf.as_mut_ptr());
-            bufs.push(buf);
-            mrs.push(mr);
-            // create qp
-            let mut qp_init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
-            qp_init_attr.qp_type = ibv_qp_type::IBV_QPT_RC;
-            qp_init_attr.sq_sig_all = 1;
-            qp_init_attr.send_cq = cq;
-            qp_init_attr.recv_cq = cq;
-            qp_init_attr.cap.max_send_wr = 1;
-            qp_init_attr.cap.max_recv_wr = 1;
-            qp_init_attr.cap.max_send_sge = 1;
-            qp_init_attr.cap.max_recv_sge = 1;
-            let qp = unsafe { ibv_create_qp(pd, &mut qp_init_attr) };
-            debug!("QP was created, QP number={:#0X}", unsafe { (*qp).qp_num });
-            qps.push(qp);
-        }
-        let kv_store: Option<HashMap<String, String>> = if config.server.is_none() {
-            Some(HashMap::new())
-        } else {
-            None
-        };
+// reject the options the device cannot do
+fn check_device_config(
+    config: &RdmaOpt,
+    port_attr: &ibv_port_attr,
+    device_attr: &ibv_device_attr,
+) -> Result<()> {
+    if let Some(mtu) = config.mtu {
+        if mtu > mtu_bytes(port_attr.max_mtu) {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "MTU {} is above the {} bytes port {} supports",
+                mtu,
+                mtu_bytes(port_attr.max_mtu),
+                config.ib_port
+            )));
+        }
+        if mtu > mtu_bytes(port_attr.active_mtu) {
+            warn!(
+                "MTU {} is above the active MTU {} of port {}",
+                mtu,
+                mtu_bytes(port_attr.active_mtu),
+                config.ib_port
+            );
+        }
+    }
+    if config.queue_depth as i64 > device_attr.max_qp_wr as i64 {
+        return Err(RdmaKvError::InvalidInput(format!(
+            "queue depth {} is above the {} work requests the device supports",
+            config.queue_depth, device_attr.max_qp_wr
+        )));
+    }
+    Ok(())
+}
 
-        Ok(RdmaContext {
-            port_attr,
-            remote_props: Default::default(), // it will set in connect_qp
-            ib_ctx,
-            pd,
-            cq,
-            qps,
-            mrs,
-            bufs,
-            kv_store,
-        })
+// address, rkey and size of an optional MR, all 0 when there is none
+fn advertise<B: RegionBuf>(
+    mr: &Option<MemoryRegion<B>>,
+    size: impl Fn(&B) -> usize,
+) -> (u64, u32, u64) {
+    mr.as_ref().map_or((0, 0, 0), |mr| {
+        (mr.addr(), mr.rkey(), size(mr.buf()) as u64)
+    })
+}
+
+// what the peers may do on a QP: everything the KV store needs
+fn qp_access_flags() -> u32 {
+    (ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+        | ibv_access_flags::IBV_ACCESS_REMOTE_READ
+        | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
+        | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC)
+        .0
+}
+
+// the conn data as CM private data, which is small: 56 bytes for a connect
+// request. Varints keep the fields that are 0 or small to a byte, and the
+// path is left to the CM
+fn encode_private(con_data: &CmConData, max_len: usize) -> Result<Vec<u8>> {
+    let private_data = handshake::encode(&CmConData {
+        path: None,
+        ..con_data.clone()
+    })?;
+    if private_data.len() > max_len {
+        return Err(RdmaKvError::Protocol(format!(
+            "conn data of {} bytes does not fit the {} bytes of private data",
+            private_data.len(),
+            max_len
+        )));
     }
+    Ok(private_data)
+}
 
-    // connect the qp , transfer the state in server side to RTR, the state in client side to RTS
-    pub fn connect_qp(&mut self, config: &RdmaOpt) -> Result<(), io::Error> {
-        // set local host
-        let mut local_gid = Gid::default();
-        if config.gidx >= 0 {
-            unsafe { ibv_query_gid(self.ib_ctx, config.ib_port, config.gidx, local_gid.ffi()) };
-        }
-        tracing::info!("qp num: {:?}", self.qps.len());
-        for i in 0..self.qps.len() {
-            let local_con_data = CmConData {
-                addr: self.bufs[i].as_ptr() as _,         // buffer address
-                rkey: unsafe { (*self.mrs[i]).rkey },     // remote key
-                qp_num: unsafe { (*self.qps[i]).qp_num }, // QP number
-                lid: self.port_attr.lid,                  // local id
-                gid: local_gid,                           // local gid
-            };
-            debug!("Local Conn  {:#0X}", local_con_data.addr);
-            let local_con_data_encoded = bincode::serialize(&local_con_data).unwrap();
-            let mut temp_con_data_encoded = bincode::serialize(&CmConData::default()).unwrap();
-            // if it is server
-            if config.server.is_none() {
-                let server_socket = SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-                    config.tcp_port as u16,
-                );
-                let listener = TcpListener::bind(server_socket).unwrap();
-                info!("Waiting for client to exchange the conn data");
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(mut stream) => {
-                            stream.write_all(&local_con_data_encoded).unwrap();
-                            stream.read_exact(&mut temp_con_data_encoded).unwrap();
-                            break;
-                        }
-                        Err(_) => {
-                            error!("Error in connect qp")
-                        }
-                    }
-                }
-            } else {
-                // if it is client
-                let client_socket = SocketAddr::new(
-                    IpAddr::from_str(config.server.as_ref().unwrap()).unwrap(),
-                    config.tcp_port as u16,
+// the CM pads the private data, the padding is ignored
+fn decode_private(private_data: &[u8]) -> Result<(u16, CmConData)> {
+    handshake::decode(private_data)
+}
+
+// a first PSN a stale or forged packet is unlikely to hit, PSNs have 24 bits
+fn random_psn() -> Result<u32> {
+    Ok(u32::from_ne_bytes(auth::random()?) & 0xff_ffff)
+}
+
+// one peer: the QP to it and the registered buffer requests and replies go
+// through. Both are on a PD of the connection's own, so the rkeys the peer
+// is told are good on its QP only, and die with it
+struct Connection {
+    qp: QueuePair,
+    mr: MemoryRegion<Vec<u8>>,
+    // the index and the counters of the device, registered for this peer
+    index_mr: Option<MemoryRegion<Arc<[AtomicU8]>>>,
+    counters_mr: Option<MemoryRegion<Arc<[AtomicU64]>>>,
+    // first PSN of the send queue, drawn again for each handshake
+    psn: u32,
+    // usable size of the buffer, negotiated with the peer
+    buf_size: usize,
+    // the smaller MTU of the two sides, negotiated with the peer
+    path_mtu: ibv_mtu::Type,
+    // the local gid the path is addressed from, None to use the LID alone
+    gid_index: Option<i32>,
+    remote_props: CmConData,
+    // the socket the conn data was exchanged on, it stays open for as long
+    // as the connection lives so that each side sees the other leave
+    stream: Option<TcpStream>,
+    // the CM id the conn data was exchanged through instead, with --cm
+    // rdmacm. It is disconnected when the connection is dropped
+    cm_id: Option<CmId>,
+    // why the QP is known to be in the error state, set by an async event
+    unhealthy: Option<String>,
+}
+
+impl Connection {
+    // an async event reported the QP as failed, nothing can be posted on it
+    // until it is brought up again
+    fn check_healthy(&self) -> Result<()> {
+        match &self.unhealthy {
+            Some(reason) => Err(RdmaKvError::connection(
+                reason.clone(),
+                io::ErrorKind::BrokenPipe.into(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // what this side tells the peer about the connection
+    fn local_props(&self, local_con_data: &CmConData) -> CmConData {
+        let (index_addr, index_rkey, index_size) = advertise(&self.index_mr, |index| index.len());
+        let (counters_addr, counters_rkey, counter_slots) =
+            advertise(&self.counters_mr, |counters| counters.len());
+        CmConData {
+            caps: Capabilities {
+                buf_size: self.mr.buf().len() as u32, // local buffer size
+                ..local_con_data.caps
+            },
+            addr: self.mr.addr(), // buffer address
+            rkey: self.mr.rkey(), // remote key
+            index_addr,
+            index_rkey,
+            index_size,
+            counters_addr,
+            counters_rkey,
+            counter_slots,
+            path: local_con_data.path.map(|path| QpPath {
+                qp_num: self.qp.qp_num(), // QP number
+                psn: self.psn,
+                ..path
+            }),
+        }
+    }
+
+    // exchange the conn data with the peer and move the QP to INIT
+    fn handshake(
+        &mut self,
+        stream: &mut TcpStream,
+        local_con_data: &CmConData,
+        config: &RdmaOpt,
+    ) -> Result<()> {
+        self.psn = random_psn()?;
+        let local_con_data = self.local_props(local_con_data);
+        stream
+            .write_all(&handshake::encode(&local_con_data)?)
+            .map_err(|e| RdmaKvError::connection("sending conn data", e))?;
+        let (version, remote_props) = handshake::read(stream)?;
+        self.negotiate(&local_con_data, version, remote_props)?;
+        self.modify_qp_to_init(config.ib_port)
+    }
+
+    // take the conn data of a peer speaking version, and settle what both
+    // sides use. What the two do not share is hidden from the rest
+    fn negotiate(
+        &mut self,
+        local_con_data: &CmConData,
+        version: u16,
+        mut remote_props: CmConData,
+    ) -> Result<()> {
+        debug!(
+            "Local Conn  {:#0X}, path {:?}",
+            local_con_data.addr, local_con_data.path
+        );
+        debug!(
+            "Remote Conn addr: {:#0X}, path {:?}",
+            remote_props.addr, remote_props.path
+        );
+        let negotiated = local_con_data.caps.negotiate(&remote_props.caps, version)?;
+        if let Some(path) = remote_props.path {
+            if self.gid_index.is_some() && !path.gid.is_global() {
+                debug!(
+                    "Peer gid {} does not route, the peer must be on the same subnet",
+                    path.gid
                 );
-                tracing::info!("Connecting to server at: {:?}", client_socket);
-                let mut stream = TcpStream::connect(client_socket).unwrap();
-                stream.write_all(&local_con_data_encoded).unwrap();
-                stream.read_exact(&mut temp_con_data_encoded).unwrap();
             }
-            self.remote_props = bincode::deserialize(&temp_con_data_encoded).unwrap();
-            debug!("Remote Conn addr: {:#0X}", self.remote_props.addr);
+        }
+        if !negotiated.has(FEATURE_INDEX) {
+            remote_props.index_size = 0;
+        }
+        if !negotiated.has(FEATURE_COUNTERS) {
+            remote_props.counter_slots = 0;
+        }
+        self.remote_props = remote_props;
+        self.buf_size = negotiated.buf_size as usize;
+        self.path_mtu = negotiated.mtu;
+        info!(
+            "Negotiated protocol version {}, opcodes {:#x}, features {:#x}",
+            negotiated.version, negotiated.opcodes, negotiated.features
+        );
+        info!("Negotiated buffer size: {}", self.buf_size);
+        info!("Negotiated path MTU: {}", mtu_bytes(self.path_mtu));
+        Ok(())
+    }
 
-            self.modify_qp_to_init(config.ib_port, i)?;
+    // move the QP from INIT to RTS once receives can be posted
+    fn ready(&self, config: &RdmaOpt) -> Result<()> {
+        let path = self
+            .remote_props
+            .path
+            .ok_or_else(|| RdmaKvError::Protocol("peer sent no QP path".to_string()))?;
+        self.modify_qp_to_rtr(config, &path)?;
+        self.modify_qp_to_rts(config)
+    }
 
-            self.modify_qp_to_rtr(
-                config,
-                self.remote_props.qp_num,
-                self.remote_props.lid,
-                self.remote_props.gid,
-                i,
-            )?;
-            self.modify_qp_to_rts(i)?;
+    // move the QP to state with the path the CM resolved. The access flags
+    // and the timers stay those of this side, the MTU the negotiated one
+    // unless the path is narrower
+    fn modify_qp_with_cm(
+        &self,
+        id: &CmId,
+        state: ibv_qp_state::Type,
+        config: &RdmaOpt,
+    ) -> Result<()> {
+        let (mut qp_attr, attr_mask) = id.init_qp_attr(state)?;
+        let context = match state {
+            ibv_qp_state::IBV_QPS_INIT => {
+                qp_attr.qp_access_flags = qp_access_flags();
+                "moving QP to INIT"
+            }
+            ibv_qp_state::IBV_QPS_RTR => {
+                qp_attr.path_mtu = qp_attr.path_mtu.min(self.path_mtu);
+                qp_attr.min_rnr_timer = config.min_rnr_timer;
+                "moving QP to RTR"
+            }
+            _ => {
+                qp_attr.timeout = config.timeout;
+                qp_attr.retry_cnt = config.retry_cnt;
+                qp_attr.rnr_retry = config.rnr_retry;
+                "moving QP to RTS"
+            }
+        };
+        self.qp.modify(&mut qp_attr, attr_mask, context)?;
+        debug!("Modify QP to state {} through the CM", state);
+        Ok(())
+    }
+
+    // what the CM tells the peer along with the conn data
+    fn conn_params<'a>(&self, private_data: &'a [u8], config: &RdmaOpt) -> ConnParams<'a> {
+        ConnParams {
+            private_data,
+            qp_num: self.qp.qp_num(),
+            retry_count: config.retry_cnt,
+            rnr_retry_count: config.rnr_retry,
         }
+    }
+
+    // a QP in the error state only leaves it through RESET, which also
+    // drops every work request still queued on it
+    fn modify_qp_to_reset(&self) -> Result<()> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        qp_attr.qp_state = ibv_qp_state::IBV_QPS_RESET;
+        self.qp.modify(
+            &mut qp_attr,
+            ibv_qp_attr_mask::IBV_QP_STATE,
+            "moving QP to RESET",
+        )?;
+        debug!("Modify QP to RESET state!");
         Ok(())
     }
 
-    pub fn modify_qp_to_init(&self, ib_port: u8, i: usize) -> Result<(), io::Error> {
+    fn modify_qp_to_init(&self, ib_port: u8) -> Result<()> {
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
         qp_attr.port_num = ib_port;
         qp_attr.pkey_index = 0;
-        qp_attr.qp_access_flags = (ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
-            | ibv_access_flags::IBV_ACCESS_REMOTE_READ
-            | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE)
-            .0;
+        qp_attr.qp_access_flags = qp_access_flags();
         let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
             | ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
             | ibv_qp_attr_mask::IBV_QP_PORT
             | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
-        let err =
-            unsafe { ibv_modify_qp(self.qps[i], &mut qp_attr, (attr_mask.0).try_into().unwrap()) };
-        if err == 0 {
-            debug!("INIT QP done");
-            Ok(())
-        } else {
-            error!("INIT QP got an error");
-            Err(io::Error::from_raw_os_error(err))
-        }
+        self.qp
+            .modify(&mut qp_attr, attr_mask, "moving QP to INIT")
+            .inspect_err(|_| {
+                error!("INIT QP got an error");
+            })?;
+        debug!("INIT QP done");
+        Ok(())
     }
 
-    pub fn modify_qp_to_rtr(
-        &self,
-        config: &RdmaOpt,
-        remote_qpn: u32,
-        dlid: u16,
-        dgid: Gid,
-        i: usize,
-    ) -> Result<(), io::Error> {
+    fn modify_qp_to_rtr(&self, config: &RdmaOpt, remote: &QpPath) -> Result<()> {
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_RTR;
-        qp_attr.path_mtu = ibv_mtu::IBV_MTU_1024;
-        qp_attr.dest_qp_num = remote_qpn;
-        qp_attr.rq_psn = 0;
+        qp_attr.path_mtu = self.path_mtu;
+        qp_attr.dest_qp_num = remote.qp_num;
+        qp_attr.rq_psn = remote.psn;
         qp_attr.max_dest_rd_atomic = 1;
-        qp_attr.min_rnr_timer = 0x12;
+        qp_attr.min_rnr_timer = config.min_rnr_timer;
         qp_attr.ah_attr.is_global = 0;
-        qp_attr.ah_attr.dlid = dlid;
-        qp_attr.ah_attr.sl = 0;
+        qp_attr.ah_attr.dlid = remote.lid;
+        qp_attr.ah_attr.sl = config.sl;
         qp_attr.ah_attr.src_path_bits = 0;
         qp_attr.ah_attr.port_num = config.ib_port;
-        if config.gidx >= 0 {
+        if let Some(gid_index) = self.gid_index {
             qp_attr.ah_attr.is_global = 1;
-            qp_attr.ah_attr.port_num = 1;
-            qp_attr.ah_attr.grh.dgid = dgid.into();
+            qp_attr.ah_attr.grh.dgid = remote.gid.into();
             qp_attr.ah_attr.grh.flow_label = 0;
-            qp_attr.ah_attr.grh.hop_limit = 1;
-            qp_attr.ah_attr.grh.sgid_index = config.gidx as _;
+            qp_attr.ah_attr.grh.hop_limit = config.hop_limit;
+            qp_attr.ah_attr.grh.sgid_index = gid_index as _;
             qp_attr.ah_attr.grh.traffic_class = 0;
         }
         let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
@@ -281,23 +451,22 @@ impl RdmaContext {
             | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
             | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
 
-        let err = unsafe { ibv_modify_qp(self.qps[i], &mut qp_attr, (attr_mask.0) as _) };
-        if err == 0 {
-            debug!("Modify QP to RTR state!");
-            Ok(())
-        } else {
-            Err(io::Error::from_raw_os_error(err))
-        }
+        self.qp
+            .modify(&mut qp_attr, attr_mask, "moving QP to RTR")?;
+        debug!("Modify QP to RTR state!");
+        Ok(())
     }
 
-    pub fn modify_qp_to_rts(&self, i: usize) -> Result<(), io::Error> {
+    fn modify_qp_to_rts(&self, config: &RdmaOpt) -> Result<()> {
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
 
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_RTS;
-        qp_attr.timeout = 0x12; // 18
-        qp_attr.retry_cnt = 6;
-        qp_attr.rnr_retry = 0;
-        qp_attr.sq_psn = 0;
+        qp_attr.timeout = config.timeout;
+        qp_attr.retry_cnt = config.retry_cnt;
+        // by default the peer may write before it posted its receives and
+        // this retries forever
+        qp_attr.rnr_retry = config.rnr_retry;
+        qp_attr.sq_psn = self.psn;
         qp_attr.max_rd_atomic = 1;
         let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
             | ibv_qp_attr_mask::IBV_QP_TIMEOUT
@@ -306,174 +475,1085 @@ impl RdmaContext {
             | ibv_qp_attr_mask::IBV_QP_SQ_PSN
             | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
 
-        let err = unsafe { ibv_modify_qp(self.qps[i], &mut qp_attr, (attr_mask.0) as _) };
-        if err == 0 {
-            debug!("Modify QP to RTS state!");
-            Ok(())
-        } else {
-            Err(io::Error::from_raw_os_error(err))
-        }
+        self.qp
+            .modify(&mut qp_attr, attr_mask, "moving QP to RTS")?;
+        debug!("Modify QP to RTS state!");
+        Ok(())
     }
 
-    // create and send a work request
-    pub fn post_send(&mut self, opcode: ibv_wr_opcode::Type, i: usize) -> Result<(), io::Error> {
+    // create and send a work request, writes and reads target remote_addr
+    fn post_send_wr(
+        &mut self,
+        wr_id: u64,
+        opcode: ibv_wr_opcode::Type,
+        len: usize,
+        remote_addr: u64,
+        rkey: u32,
+    ) -> Result<u64> {
+        self.check_healthy()?;
+        if len > self.buf_size {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "{} bytes exceed the {} byte buffer",
+                len, self.buf_size
+            )));
+        }
         let mut sge = unsafe { std::mem::zeroed::<ibv_sge>() };
-        sge.addr = self.bufs[i].as_mut_ptr() as _;
-        sge.length = BUFFER_SIZE as _;
-        sge.lkey = unsafe { (*self.mrs[i]).lkey };
+        sge.addr = self.mr.addr();
+        sge.length = len as _;
+        sge.lkey = self.mr.lkey();
 
         let mut send_wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
         send_wr.next = std::ptr::null_mut();
-        send_wr.wr_id = 0;
+        send_wr.wr_id = wr_id;
         send_wr.sg_list = &mut sge;
         send_wr.num_sge = 1;
         send_wr.opcode = opcode;
         send_wr.send_flags = (ibv_send_flags::IBV_SEND_SIGNALED).0;
 
-        if opcode != ibv_wr_opcode::IBV_WR_SEND {
-            send_wr.wr.rdma.remote_addr = self.remote_props.addr;
-            send_wr.wr.rdma.rkey = self.remote_props.rkey;
-        }
-        let mut bad_wr: *mut ibv_send_wr = std::ptr::null_mut();
-        let err = unsafe { ibv_post_send(self.qps[i], &mut send_wr, &mut bad_wr) };
-        if err == 0 {
-            match opcode {
-                ibv_wr_opcode::IBV_WR_SEND => debug!("Send request was posted"),
-                ibv_wr_opcode::IBV_WR_RDMA_READ => debug!("RDMA read request was posted"),
-                ibv_wr_opcode::IBV_WR_RDMA_WRITE => debug!("RDMA write request was posted"),
-                _ => debug!("Unknown request was posted"),
+        if opcode == ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM
+            || opcode == ibv_wr_opcode::IBV_WR_SEND_WITH_IMM
+        {
+            send_wr.imm_data_invalidated_rkey_union.imm_data = (len as u32).to_be();
+        }
+        if opcode != ibv_wr_opcode::IBV_WR_SEND && opcode != ibv_wr_opcode::IBV_WR_SEND_WITH_IMM {
+            send_wr.wr.rdma.remote_addr = remote_addr;
+            send_wr.wr.rdma.rkey = rkey;
+        }
+        // the sge lies in the connection's own MR
+        unsafe { self.qp.post_send(&mut send_wr) }?;
+        match opcode {
+            ibv_wr_opcode::IBV_WR_SEND => debug!("Send request was posted"),
+            ibv_wr_opcode::IBV_WR_RDMA_READ => debug!("RDMA read request was posted"),
+            ibv_wr_opcode::IBV_WR_RDMA_WRITE => debug!("RDMA write request was posted"),
+            ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM => {
+                debug!("RDMA write with immediate request was posted")
             }
-            Ok(())
-        } else {
-            Err(io::Error::from_raw_os_error(err))
+            _ => debug!("Unknown request was posted"),
         }
+        Ok(send_wr.wr_id)
+    }
+
+    fn post_atomic(
+        &mut self,
+        wr_id: u64,
+        opcode: ibv_wr_opcode::Type,
+        remote_addr: u64,
+        rkey: u32,
+        compare_add: u64,
+        swap: u64,
+    ) -> Result<u64> {
+        self.check_healthy()?;
+        let mut sge = unsafe { std::mem::zeroed::<ibv_sge>() };
+        sge.addr = self.mr.addr();
+        sge.length = 8;
+        sge.lkey = self.mr.lkey();
+
+        let mut send_wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
+        send_wr.next = std::ptr::null_mut();
+        send_wr.wr_id = wr_id;
+        send_wr.sg_list = &mut sge;
+        send_wr.num_sge = 1;
+        send_wr.opcode = opcode;
+        send_wr.send_flags = (ibv_send_flags::IBV_SEND_SIGNALED).0;
+        send_wr.wr.atomic.remote_addr = remote_addr;
+        send_wr.wr.atomic.compare_add = compare_add;
+        send_wr.wr.atomic.swap = swap;
+        send_wr.wr.atomic.rkey = rkey;
+
+        // the sge lies in the connection's own MR
+        unsafe { self.qp.post_send(&mut send_wr) }?;
+        debug!("Atomic request {} was posted", opcode);
+        Ok(send_wr.wr_id)
     }
 
-    pub fn post_receive(&mut self, i: usize) -> Result<(), io::Error> {
+    fn post_receive(&mut self, wr_id: u64) -> Result<u64> {
+        self.check_healthy()?;
         let mut sge = unsafe { std::mem::zeroed::<ibv_sge>() };
-        sge.addr = self.bufs[i].as_mut_ptr() as _;
-        sge.length = BUFFER_SIZE as _;
-        sge.lkey = unsafe { (*self.mrs[i]).lkey };
+        sge.addr = self.mr.addr();
+        sge.length = self.buf_size as _;
+        sge.lkey = self.mr.lkey();
 
         let mut recv_wr = unsafe { std::mem::zeroed::<ibv_recv_wr>() };
         recv_wr.next = std::ptr::null_mut();
-        recv_wr.wr_id = 100;
+        recv_wr.wr_id = wr_id;
         recv_wr.sg_list = &mut sge;
         recv_wr.num_sge = 1;
 
-        let mut bad_wr: *mut ibv_recv_wr = std::ptr::null_mut();
-        let err = unsafe { ibv_post_recv(self.qps[i], &mut recv_wr, &mut bad_wr) };
-        if err == 0 {
-            debug!("Receive request was posted");
-            Ok(())
-        } else {
-            error!("Receive request posted got an error, Wr ID: {}", unsafe {
-                (*bad_wr).wr_id
-            });
-            Err(io::Error::from_raw_os_error(err))
+        // the sge lies in the connection's own MR
+        unsafe { self.qp.post_recv(&mut recv_wr) }.inspect_err(|_| {
+            error!("Receive request posted got an error, Wr ID: {}", wr_id);
+        })?;
+        debug!("Receive request was posted");
+        Ok(recv_wr.wr_id)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(stream) = &self.stream {
+            // wakes up the thread watching the socket too
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(id) = &self.cm_id {
+            id.disconnect();
         }
     }
+}
 
-    pub fn poll_completion(&self) -> Result<(), io::Error> {
-        let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
-        let mut poll_result: i32;
-        loop {
-            poll_result = unsafe { ibv_poll_cq(self.cq, 1, &mut wc) };
-            if poll_result != 0 {
-                break;
+// the opened device and the connections, the accept loop shares it with
+// the worker so that clients can join while the others are served
+struct Device {
+    // the channel fd registered with tokio, on the first async wait. It is
+    // declared first so that it leaves the reactor before the channel closes
+    comp_fd: OnceCell<AsyncFd<RawFd>>,
+    port_attr: ibv_port_attr,
+    ctx: Arc<DeviceContext>,
+    cq: Arc<CompletionQueue>,
+    // the hash index exposed for one-sided gets, None when it is empty.
+    // Each connection registers it on its own PD
+    index: Option<Arc<[AtomicU8]>>,
+    // the counter slots exposed for RDMA atomics, same as the index
+    counters: Option<Arc<[AtomicU64]>>,
+    // connections by id, ids are never reused so that the completions of a
+    // connection that is gone cannot be taken for those of a new one
+    conns: Mutex<HashMap<usize, Connection>>,
+    // sequence number of the next work request, see make_wr_id
+    next_wr_seq: AtomicU32,
+    // the gid of the port that is used, None to use the LID alone
+    gid_index: Option<i32>,
+    // whether the HCA can do RDMA atomics
+    atomics: bool,
+    // state of the port as last reported by an async event
+    port_active: AtomicBool,
+    // the last async events, for the status
+    events: Mutex<EventLog>,
+}
+
+impl Device {
+    fn next_wr_id(&self, i: usize) -> u64 {
+        make_wr_id(i, self.next_wr_seq.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // create the PD, buffer, MRs and QP of a new connection
+    fn create_connection(&self, config: &RdmaOpt) -> Result<Connection> {
+        let pd = ProtectionDomain::new(&self.ctx)?;
+        let mr_access_flags = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+            | ibv_access_flags::IBV_ACCESS_REMOTE_READ
+            | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE;
+        let mr = MemoryRegion::register(&pd, vec![0; config.buf_size], mr_access_flags)?;
+        // read only for the peers
+        let index_mr = self
+            .index
+            .as_ref()
+            .map(|index| {
+                let mr_access_flags = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+                    | ibv_access_flags::IBV_ACCESS_REMOTE_READ;
+                MemoryRegion::register(&pd, index.clone(), mr_access_flags)
+            })
+            .transpose()?;
+        let counters_mr = self
+            .counters
+            .as_ref()
+            .map(|counters| {
+                let mr_access_flags = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+                    | ibv_access_flags::IBV_ACCESS_REMOTE_READ
+                    | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+                MemoryRegion::register(&pd, counters.clone(), mr_access_flags)
+            })
+            .transpose()?;
+        let qp = QueuePair::new(&pd, &self.cq, config.queue_depth)?;
+        Ok(Connection {
+            qp,
+            index_mr,
+            counters_mr,
+            psn: 0, // it will set in handshake
+            buf_size: mr.buf().len(),
+            path_mtu: ibv_mtu::IBV_MTU_256, // it will set in handshake
+            gid_index: self.gid_index,
+            mr,
+            remote_props: Default::default(), // it will set in handshake
+            stream: None,
+            cm_id: None,
+            unhealthy: None,
+        })
+    }
+
+    // conn data of the port and of what this side exposes to its peers, the
+    // rest is filled in by the connection
+    fn local_con_data(&self, config: &RdmaOpt) -> CmConData {
+        // set local host
+        let mut local_gid = Gid::default();
+        if let Some(gid_index) = self.gid_index {
+            match self.ctx.query_gid(config.ib_port, gid_index) {
+                Ok(gid) => local_gid = gid,
+                Err(e) => warn!("Query of gid {} failed: {}", gid_index, e),
             }
         }
-        if poll_result < 0 {
-            error!("Poll CQ failed");
-            Err(io::Error::from_raw_os_error(poll_result))
-        } else if wc.status != ibv_wc_status::IBV_WC_SUCCESS {
-            error!(
-                "got bad completion with status: {:#0X}, vendor syndrome: {:#0X}",
-                wc.status, wc.vendor_err
-            );
-            Err(io::Error::new(io::ErrorKind::InvalidData, "WC Failed"))
+        let atomics = if self.atomics { OP_ATOMIC } else { 0 };
+        // a server offers what it exposes, a client takes whatever it is
+        // offered
+        let features = if config.server.is_some() {
+            ALL_FEATURES
         } else {
-            Ok(())
+            (if self.index.is_some() {
+                FEATURE_INDEX
+            } else {
+                0
+            }) | (if self.counters.is_some() {
+                FEATURE_COUNTERS
+            } else {
+                0
+            })
+        };
+        CmConData {
+            caps: Capabilities {
+                opcodes: OP_WRITE_WITH_IMM | OP_RDMA_READ | atomics,
+                features,
+                queue_depth: config.queue_depth,
+                mtu: config
+                    .mtu
+                    .and_then(mtu_from_bytes)
+                    .unwrap_or(self.port_attr.active_mtu),
+                ..Default::default()
+            },
+            path: Some(QpPath {
+                lid: self.port_attr.lid, // local id
+                gid: local_gid,          // local gid
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    // the CM picks the device of a connection from the addresses, the QP is
+    // on the one opened here
+    fn check_cm_device(&self, id: &CmId) -> Result<()> {
+        let opened = self.ctx.name();
+        match id.device_name() {
+            Some(name) if name == opened => Ok(()),
+            name => Err(RdmaKvError::device(
+                format!(
+                    "the CM reaches the peer through {}, not the opened {}",
+                    name.as_deref().unwrap_or("no device"),
+                    opened
+                ),
+                io::ErrorKind::InvalidInput.into(),
+            )),
         }
     }
 
-    pub fn set_bytes_to_buf(&mut self, new_bytes: &mut Vec<u8>, i: usize) {
-        new_bytes.resize(BUFFER_SIZE, 0);
-        self.bufs[i].copy_from_slice(new_bytes);
-        debug!("buf addr: {:p}", self.bufs[i].as_ptr());
+    // drop connection i, false when it was gone already
+    fn disconnect(&self, i: usize) -> bool {
+        // dropping the connection deregisters its MRs, which revokes the
+        // rkeys the peer was told
+        let conn = self.conns.lock().unwrap().remove(&i);
+        conn.is_some()
     }
 
-    pub fn check_the_buf(&self, i: usize) {
-        info!(
-            "current buf in RDMA context is: {:#?}",
-            std::str::from_utf8(&self.bufs[i]).unwrap()
-        );
+    fn status(&self) -> TransportStatus {
+        let mut connections: Vec<_> = self
+            .conns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, conn)| ConnectionStatus {
+                id,
+                qp_num: Some(conn.qp.qp_num()),
+                healthy: conn.unhealthy.is_none(),
+                reason: conn.unhealthy.clone(),
+            })
+            .collect();
+        connections.sort_by_key(|conn| conn.id);
+        TransportStatus {
+            port_active: self.port_active.load(Ordering::Relaxed),
+            connections,
+            events: self.events.lock().unwrap().records(),
+        }
     }
 
-    pub fn read_the_buf(&self, i: usize) -> String {
-        let valid_content: Vec<u8> = self.bufs[i]
-            .split(|&x| x == 0)
-            .next()
-            .unwrap_or(&[])
-            .to_vec();
-        serde_json::from_slice::<String>(&valid_content).unwrap()
+    // log an async event and apply it to the port and the connections
+    fn handle_event(&self, event: RdmaEvent) {
+        match event {
+            RdmaEvent::PortActive { .. } => self.port_active.store(true, Ordering::Relaxed),
+            RdmaEvent::PortError { .. } => self.port_active.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+        let mut conns = self.conns.lock().unwrap();
+        if let Some(qp_num) = event.failed_qp() {
+            match conns
+                .iter_mut()
+                .find(|(_, conn)| conn.qp.qp_num() == qp_num)
+            {
+                Some((i, conn)) => {
+                    error!("Connection {}: {}", i, event);
+                    conn.unhealthy = Some(event.to_string());
+                }
+                None => warn!("{}, it belongs to no connection", event),
+            }
+        } else if event == RdmaEvent::DeviceFatal {
+            error!("{}", event);
+            for conn in conns.values_mut() {
+                conn.unhealthy = Some(event.to_string());
+            }
+        } else if event.is_fatal() {
+            error!("{}", event);
+        } else if matches!(event, RdmaEvent::PortActive { .. }) {
+            info!("{}", event);
+        } else {
+            debug!("{}", event);
+        }
+        drop(conns);
+        self.events.lock().unwrap().push(&event);
     }
+}
 
-    pub fn process_kv_opt(&mut self) {
-        let mut bufs_clone = self.bufs.clone();
+// read the async events of the device for as long as the device is used
+fn monitor(device: Weak<Device>, ctx: Arc<DeviceContext>, fd: RawFd) {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        if unsafe { libc::poll(&mut pollfd, 1, MONITOR_INTERVAL) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                error!("Waiting for async events failed: {}", err);
+                return;
+            }
+        }
+        let Some(device) = device.upgrade() else {
+            return;
+        };
         loop {
-            for i in 0..bufs_clone.len() {
-                if bufs_clone[i] == self.bufs[i] || self.bufs[i].is_empty() {
+            match ctx.get_async_event() {
+                Ok(Some(event)) => device.handle_event(event.into()),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// accepts clients for as long as the server runs
+struct Acceptor {
+    device: Arc<Device>,
+    config: RdmaOpt,
+    // what every client is told about the port and the device
+    local_con_data: CmConData,
+    // the secrets clients are admitted with
+    keys: Keys,
+    next_id: AtomicUsize,
+    // the clients being admitted
+    handshakes: AtomicUsize,
+}
+
+impl Acceptor {
+    fn new(device: Arc<Device>, config: &RdmaOpt, keys: Keys) -> Self {
+        Acceptor {
+            local_con_data: device.local_con_data(config),
+            device,
+            config: config.clone(),
+            keys,
+            next_id: AtomicUsize::new(0),
+            handshakes: AtomicUsize::new(0),
+        }
+    }
+
+    // a client that is slow to answer only holds up its own admission, the
+    // others are admitted on their own threads meanwhile
+    fn run(self, listener: TcpListener) {
+        let acceptor = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Client could not connect: {}", e);
                     continue;
                 }
-                println!("time: {:?}", Instant::now());
-                self.check_the_buf(i);
-                let valid_content: Vec<u8> = self.bufs[i]
-                    .split(|&x| x == 0)
-                    .next()
-                    .unwrap_or(&[])
-                    .to_vec();
-                let kv_opt = serde_json::from_slice::<KeyValueOpt>(&valid_content).unwrap();
-                match kv_opt {
-                    KeyValueOpt::Set { key, value } => {
-                        self.kv_store.as_mut().unwrap().insert(key, value);
-                        tracing::info!("kv store: {:#?}", self.kv_store);
+            };
+            if acceptor.handshakes.fetch_add(1, Ordering::AcqRel) >= MAX_HANDSHAKES {
+                acceptor.handshakes.fetch_sub(1, Ordering::AcqRel);
+                warn!(
+                    "Client could not connect: {} clients are being admitted already",
+                    MAX_HANDSHAKES
+                );
+                continue;
+            }
+            let acceptor = acceptor.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = acceptor.accept(stream) {
+                    warn!("Client could not connect: {}", e);
+                }
+                acceptor.handshakes.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    }
+
+    // fails when who would be one client too many
+    fn check_room(&self, conns: &HashMap<usize, Connection>, who: &str) -> Result<()> {
+        if conns.len() < self.config.max_clients {
+            return Ok(());
+        }
+        Err(RdmaKvError::connection(
+            format!(
+                "{} is turned away, {} clients are connected",
+                who, self.config.max_clients
+            ),
+            io::ErrorKind::ConnectionRefused.into(),
+        ))
+    }
+
+    fn accept(&self, mut stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr()?;
+        self.check_room(&self.device.conns.lock().unwrap(), &peer.to_string())?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        // nothing is registered for a client until it is admitted
+        let name = auth::admit(&mut stream, &self.keys, peer)?;
+        let i = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut conn = self.device.create_connection(&self.config)?;
+        conn.handshake(&mut stream, &self.local_con_data, &self.config)?;
+        // the client may write its first request as soon as the QP is up
+        conn.post_receive(self.device.next_wr_id(i))?;
+        conn.ready(&self.config)?;
+        stream.set_read_timeout(None)?;
+        let watched = stream.try_clone()?;
+        conn.stream = Some(stream);
+        // others may have been admitted while this one was
+        let mut conns = self.device.conns.lock().unwrap();
+        self.check_room(&conns, &peer.to_string())?;
+        conns.insert(i, conn);
+        drop(conns);
+        info!("Client {} connected from {} as {:?}", i, peer, name);
+
+        let device = self.device.clone();
+        std::thread::spawn(move || watch(device, i, watched));
+        Ok(())
+    }
+
+    // the CM version of run, the events of every client arrive on channel
+    fn run_cm(self, channel: Arc<EventChannel>, _listener: CmId) {
+        loop {
+            let event = match channel.get_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("No more clients are accepted: {}", e);
+                    return;
+                }
+            };
+            match event.event {
+                rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_REQUEST => {
+                    let Some(id) = event.request else { continue };
+                    if let Err(e) = self.accept_cm(id, &event.private_data) {
+                        warn!("Client could not connect: {}", e);
                     }
-                    KeyValueOpt::Get { key } => {
-                        let value = self.kv_store.as_ref().unwrap().get(&key);
-                        let mut send_str =
-                            serde_json::to_vec(&value.unwrap_or(&"key not found".to_string()))
-                                .unwrap();
-                        self.set_bytes_to_buf(&mut send_str, i);
-                        self.post_send(ibv_wr_opcode::IBV_WR_SEND, i).unwrap();
-                        self.poll_completion().unwrap();
+                }
+                rdma_cm_event_type::RDMA_CM_EVENT_ESTABLISHED => {
+                    if let Some(i) = event.context {
+                        debug!("Client {} is established", i);
                     }
-                    KeyValueOpt::Delete { key } => {
-                        self.kv_store.as_mut().unwrap().remove(&key);
-                        tracing::info!("kv store: {:#?}", self.kv_store);
+                }
+                rdma_cm_event_type::RDMA_CM_EVENT_DISCONNECTED => {
+                    if let Some(i) = event.context.filter(|&i| self.device.disconnect(i)) {
+                        info!("Client {} left", i);
                     }
                 }
-                bufs_clone[i] = self.bufs[i].clone();
+                rdma_cm_event_type::RDMA_CM_EVENT_DEVICE_REMOVAL => {
+                    error!("The device was removed");
+                }
+                other => debug!("Ignored CM event {}", cm::event_str(other)),
             }
         }
     }
+
+    // accept the connect request of id, or reject it
+    fn accept_cm(&self, id: CmId, private_data: &[u8]) -> Result<()> {
+        let (i, mut conn, private_data) = match self.prepare_cm(&id, private_data) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                id.reject(&e.to_string());
+                return Err(e);
+            }
+        };
+        // the connection is in place before the first request can complete
+        let mut conns = self.device.conns.lock().unwrap();
+        id.accept(&conn.conn_params(&private_data, &self.config))?;
+        info!("Client {} connected through port {}", i, id.port_num());
+        conn.cm_id = Some(id);
+        conns.insert(i, conn);
+        Ok(())
+    }
+
+    // bring up a connection for the connect request of id, the QP is in RTS
+    // before the request is accepted. Returns it with its id and the conn
+    // data the accept carries
+    fn prepare_cm(&self, id: &CmId, private_data: &[u8]) -> Result<(usize, Connection, Vec<u8>)> {
+        self.check_room(&self.device.conns.lock().unwrap(), "client")?;
+        self.device.check_cm_device(id)?;
+        let i = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut conn = self.device.create_connection(&self.config)?;
+        let local_con_data = conn.local_props(&self.local_con_data);
+        let (version, remote_props) = decode_private(private_data)?;
+        conn.negotiate(&local_con_data, version, remote_props)?;
+        conn.modify_qp_with_cm(id, ibv_qp_state::IBV_QPS_INIT, &self.config)?;
+        // the client may write its first request as soon as it is accepted
+        conn.post_receive(self.device.next_wr_id(i))?;
+        conn.modify_qp_with_cm(id, ibv_qp_state::IBV_QPS_RTR, &self.config)?;
+        conn.modify_qp_with_cm(id, ibv_qp_state::IBV_QPS_RTS, &self.config)?;
+        let private_data = encode_private(&local_con_data, CM_ACCEPT_PRIVATE_DATA)?;
+        id.set_context(i);
+        Ok((i, conn, private_data))
+    }
 }
 
-impl Drop for RdmaContext {
-    fn drop(&mut self) {
-        for i in 0..self.qps.len() {
-            let err = unsafe { ibv_destroy_qp(self.qps[i]) };
-            assert_eq!(err, 0);
-        }
-        for i in 0..self.mrs.len() {
-            let err = unsafe { ibv_dereg_mr(self.mrs[i]) };
-            assert_eq!(err, 0);
-        }
-        let err = unsafe { ibv_destroy_cq(self.cq) };
-        assert_eq!(err, 0);
-        let err = unsafe { ibv_dealloc_pd(self.pd) };
-        assert_eq!(err, 0);
-        let err = unsafe { ibv_close_device(self.ib_ctx) };
-        assert_eq!(err, 0);
+// the next event on channel, which must be expected
+fn expect_event(
+    channel: &Arc<EventChannel>,
+    expected: rdma_cm_event_type::Type,
+) -> Result<CmEvent> {
+    let event = channel.get_event()?;
+    if event.event == expected {
+        return Ok(event);
+    }
+    // a server rejecting a client says why in the private data
+    let reason = String::from_utf8_lossy(&event.private_data);
+    let reason = reason.trim_end_matches('\0');
+    Err(RdmaKvError::connection(
+        format!(
+            "waiting for {}, got {} with status {}{}{}",
+            cm::event_str(expected),
+            cm::event_str(event.event),
+            event.status,
+            if reason.is_empty() { "" } else { ": " },
+            reason
+        ),
+        io::ErrorKind::ConnectionRefused.into(),
+    ))
+}
+
+// nothing is written to the socket after the handshake, so the read only
+// returns once the client is gone or the connection was dropped here
+fn watch(device: Arc<Device>, i: usize, mut stream: TcpStream) {
+    let _ = stream.read(&mut [0; 1]);
+    if device.disconnect(i) {
+        info!("Client {} left", i);
+    }
+}
+
+/// The ibverbs transport.
+///
+/// A server accepts clients in the background once it listens, each one gets
+/// its own PD, QP, MRs and buffer, addressed by an id that is never reused.
+/// The rkeys a client is told only work on its QP, and stop working once it
+/// disconnects. A
+/// client has a single connection, with id 0. The conn data is exchanged
+/// over a TCP socket, or carried by the RDMA CM with `--cm rdmacm`.
+pub struct RdmaContext {
+    device: Arc<Device>,
+}
+
+/// The status of an `RdmaContext`, for a thread other than the one using
+/// it, such as the HTTP server of a `KvServer`.
+#[derive(Clone)]
+pub struct StatusHandle {
+    device: Arc<Device>,
+}
+
+impl StatusHandle {
+    pub fn status(&self) -> TransportStatus {
+        self.device.status()
+    }
+}
+
+impl RdmaContext {
+    pub fn status_handle(&self) -> StatusHandle {
+        StatusHandle {
+            device: self.device.clone(),
+        }
+    }
+
+    pub fn create(config: &RdmaOpt) -> Result<Self> {
+        check_config(config)?;
+        let ib_ctx = DeviceContext::open(config.ib_devname.as_deref())?;
+        // query port properties
+        let port_attr = ib_ctx.query_port(config.ib_port)?;
+        let device_attr = ib_ctx.query_device()?;
+        check_device_config(config, &port_attr, &device_attr)?;
+        let gid_index = match config.gidx {
+            GidIndex::Auto => Some(devices::auto_gid_index(
+                &ib_ctx,
+                config.ib_port,
+                &port_attr,
+                config.server.as_deref(),
+            )),
+            GidIndex::Index(index) => (index >= 0).then_some(index),
+            GidIndex::Gid(gid) => Some(
+                devices::gid_table(&ib_ctx, config.ib_port, &port_attr)
+                    .iter()
+                    .find(|entry| entry.gid == gid)
+                    .map(|entry| entry.index)
+                    .ok_or_else(|| {
+                        RdmaKvError::InvalidInput(format!(
+                            "port {} has no gid {}",
+                            config.ib_port, gid
+                        ))
+                    })?,
+            ),
+        };
+        let comp_channel = if config.comp_channel {
+            Some(CompChannel::new(&ib_ctx)?)
+        } else {
+            None
+        };
+        let max_conns = if config.server.is_some() {
+            1
+        } else {
+            config.max_clients
+        };
+        // create cq, large enough for every queue of every QP to be full
+        let cq_size = max_conns * 2 * config.queue_depth as usize;
+        let cq_size: i32 = cq_size.try_into().map_err(|_| {
+            RdmaKvError::InvalidInput(format!("CQ of {} entries is too large", cq_size))
+        })?;
+        if cq_size > device_attr.max_cqe {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "CQ of {} entries is above the {} the device supports",
+                cq_size, device_attr.max_cqe
+            )));
+        }
+        let cq = CompletionQueue::new(&ib_ctx, cq_size, comp_channel)?;
+        let async_fd = ib_ctx.set_async_nonblocking()?;
+        // only the server exposes an index, read only for the clients
+        let index = (config.server.is_none() && config.index_buckets > 0).then(|| {
+            (0..index::region_size(config.index_buckets))
+                .map(|_| AtomicU8::new(0))
+                .collect()
+        });
+        // the server changes counters with CPU atomics too, which only mix
+        // with the atomics of the clients on an HCA that makes them global
+        let counters = if config.server.is_none() && config.counter_slots > 0 {
+            if device_attr.atomic_cap == ibv_atomic_cap::IBV_ATOMIC_GLOB {
+                Some(
+                    (0..config.counter_slots)
+                        .map(|_| AtomicU64::new(0))
+                        .collect(),
+                )
+            } else {
+                warn!("Device has no global atomics, counters go through the server");
+                None
+            }
+        } else {
+            None
+        };
+        let device = Arc::new(Device {
+            comp_fd: OnceCell::new(),
+            port_attr,
+            ctx: ib_ctx.clone(),
+            cq,
+            index,
+            counters,
+            conns: Mutex::new(HashMap::new()),
+            next_wr_seq: AtomicU32::new(0),
+            gid_index,
+            atomics: device_attr.atomic_cap != ibv_atomic_cap::IBV_ATOMIC_NONE,
+            port_active: AtomicBool::new(port_attr.state == ibv_port_state::IBV_PORT_ACTIVE),
+            events: Mutex::new(EventLog::default()),
+        });
+        if !device.port_active.load(Ordering::Relaxed) {
+            warn!("Port {} is not active", config.ib_port);
+        }
+        let weak = Arc::downgrade(&device);
+        std::thread::spawn(move || monitor(weak, ib_ctx, async_fd));
+        Ok(RdmaContext { device })
+    }
+
+    // bring conn up with the server of config, over a new socket
+    fn connect_conn(&self, conn: &mut Connection, config: &RdmaOpt) -> Result<()> {
+        let server = config
+            .server
+            .as_ref()
+            .ok_or_else(|| RdmaKvError::InvalidInput("no server to connect to".to_string()))?;
+        let client_socket = SocketAddr::new(
+            IpAddr::from_str(server).map_err(|e| {
+                RdmaKvError::InvalidInput(format!("server address {}: {}", server, e))
+            })?,
+            config.tcp_port as u16,
+        );
+        tracing::info!("Connecting to server at: {:?}", client_socket);
+        if config.cm == ConnManager::Rdmacm {
+            return self.connect_conn_cm(conn, config, client_socket);
+        }
+        let mut stream = TcpStream::connect(client_socket)
+            .map_err(|e| RdmaKvError::connection(format!("connecting to {}", client_socket), e))?;
+        auth::prove(&mut stream, &config.name, config.secret.as_ref())?;
+        conn.handshake(&mut stream, &self.device.local_con_data(config), config)?;
+        conn.ready(config)?;
+        conn.stream = Some(stream);
+        Ok(())
+    }
+
+    // the CM version of connect_conn: the CM resolves the path to server and
+    // carries the conn data in its connect request and reply
+    fn connect_conn_cm(
+        &self,
+        conn: &mut Connection,
+        config: &RdmaOpt,
+        server: SocketAddr,
+    ) -> Result<()> {
+        let channel = EventChannel::new()?;
+        let id = CmId::new(&channel)?;
+        id.resolve_addr(server, CM_RESOLVE_TIMEOUT)?;
+        expect_event(&channel, rdma_cm_event_type::RDMA_CM_EVENT_ADDR_RESOLVED)?;
+        self.device.check_cm_device(&id)?;
+        id.resolve_route(CM_RESOLVE_TIMEOUT)?;
+        expect_event(&channel, rdma_cm_event_type::RDMA_CM_EVENT_ROUTE_RESOLVED)?;
+        conn.modify_qp_with_cm(&id, ibv_qp_state::IBV_QPS_INIT, config)?;
+        let local_con_data = conn.local_props(&self.device.local_con_data(config));
+        let private_data = encode_private(&local_con_data, CM_CONNECT_PRIVATE_DATA)?;
+        id.connect(&conn.conn_params(&private_data, config))?;
+        // the QP is not the id's, so the connect stops at the response
+        // until it is established by hand
+        let response = expect_event(&channel, rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_RESPONSE)?;
+        let (version, remote_props) = decode_private(&response.private_data)?;
+        conn.negotiate(&local_con_data, version, remote_props)?;
+        conn.modify_qp_with_cm(&id, ibv_qp_state::IBV_QPS_RTR, config)?;
+        conn.modify_qp_with_cm(&id, ibv_qp_state::IBV_QPS_RTS, config)?;
+        id.establish()?;
+        info!("Connected through port {}", id.port_num());
+        conn.cm_id = Some(id);
+        Ok(())
+    }
+
+    // run f on connection i, which fails when it is gone
+    fn with_conn<R>(&self, i: usize, f: impl FnOnce(&mut Connection) -> Result<R>) -> Result<R> {
+        let mut conns = self.device.conns.lock().unwrap();
+        f(conns
+            .get_mut(&i)
+            .ok_or_else(|| RdmaKvError::not_connected(i))?)
+    }
+
+    // poll the CQ once, Ok(None) means it was empty
+    fn try_poll_completion(&self) -> Result<Option<Completion>> {
+        let Some(wc) = self
+            .device
+            .cq
+            .poll()
+            .inspect_err(|_| error!("Poll CQ failed"))?
+        else {
+            return Ok(None);
+        };
+        if wc.status != ibv_wc_status::IBV_WC_SUCCESS {
+            error!(
+                "got bad completion with status: {:#0X}, vendor syndrome: {:#0X}, wr_id: {:#0X}",
+                wc.status, wc.vendor_err, wc.wr_id
+            );
+        }
+        Ok(Some(Completion {
+            wr_id: wc.wr_id,
+            qp: wr_id_qp(wc.wr_id),
+            status: wc.status,
+            opcode: wc.opcode,
+            byte_len: wc.byte_len,
+            imm_data: (wc.wc_flags & ibv_wc_flags::IBV_WC_WITH_IMM.0 != 0)
+                .then(|| u32::from_be(unsafe { wc.imm_data_invalidated_rkey_union.imm_data })),
+        }))
+    }
+
+    // sleep until the completion channel fd is readable
+    fn wait_comp_channel(&self, fd: RawFd, timeout: Option<Duration>) -> Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().clamp(1, i32::MAX as u128) as i32);
+        if unsafe { libc::poll(&mut pollfd, 1, timeout) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(RdmaKvError::resource("waiting on completion channel", err));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for RdmaContext {
+    fn buf_size(&self, i: usize) -> usize {
+        self.with_conn(i, |conn| Ok(conn.buf_size)).unwrap_or(0)
+    }
+
+    // connect to the server, a connection made before is replaced
+    fn connect(&mut self, config: &RdmaOpt) -> Result<()> {
+        let mut conn = self.device.create_connection(config)?;
+        self.connect_conn(&mut conn, config)?;
+        let old = self.device.conns.lock().unwrap().insert(0, conn);
+        drop(old);
+        Ok(())
+    }
+
+    // the QP and MR are kept, the QP goes through RESET and is brought up
+    // again with the conn data of a new handshake. The server dropped its
+    // end when the QP failed, so it sees a new client
+    fn reconnect(&mut self, config: &RdmaOpt) -> Result<()> {
+        let conn = self.device.conns.lock().unwrap().remove(&0);
+        let Some(mut conn) = conn else {
+            return self.connect(config);
+        };
+        if let Some(stream) = conn.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(id) = conn.cm_id.take() {
+            id.disconnect();
+        }
+        conn.modify_qp_to_reset()?;
+        conn.unhealthy = None;
+        self.connect_conn(&mut conn, config)?;
+        self.device.conns.lock().unwrap().insert(0, conn);
+        Ok(())
+    }
+
+    // the connections live in the device, a second handle to it brings
+    // connection 0 back on a blocking thread of the runtime
+    fn reconnect_async(&mut self, config: &RdmaOpt) -> impl Future<Output = Result<()>> + Send {
+        let mut context = RdmaContext {
+            device: self.device.clone(),
+        };
+        let config = config.clone();
+        async move {
+            tokio::task::spawn_blocking(move || context.reconnect(&config))
+                .await
+                .map_err(|e| RdmaKvError::connection("reconnecting", io::Error::other(e)))?
+        }
+    }
+
+    fn listen(&mut self, config: &RdmaOpt) -> Result<()> {
+        let server_socket = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            config.tcp_port as u16,
+        );
+        let keys = Keys::new(config.secret.as_ref(), config.tokens.as_deref())?;
+        if let Keys::Open = keys {
+            warn!(
+                "Clients are not authenticated, anyone reaching port {} can write to the buffers",
+                config.tcp_port
+            );
+        }
+        let acceptor = Acceptor::new(self.device.clone(), config, keys);
+        if config.cm == ConnManager::Rdmacm {
+            let channel = EventChannel::new()?;
+            let listener = CmId::new(&channel)?;
+            listener.listen(
+                server_socket,
+                config.max_clients.min(i32::MAX as usize) as i32,
+            )?;
+            info!("Waiting for clients to connect through the CM");
+            std::thread::spawn(move || acceptor.run_cm(channel, listener));
+            return Ok(());
+        }
+        let listener = TcpListener::bind(server_socket)
+            .map_err(|e| RdmaKvError::connection(format!("listening on {}", server_socket), e))?;
+        info!("Waiting for clients to exchange the conn data");
+        std::thread::spawn(move || acceptor.run(listener));
+        Ok(())
+    }
+
+    fn disconnect(&mut self, i: usize) {
+        if self.device.disconnect(i) {
+            info!("Connection {} was dropped", i);
+        }
+    }
+
+    fn post_send(&mut self, opcode: ibv_wr_opcode::Type, i: usize, len: usize) -> Result<u64> {
+        let wr_id = self.device.next_wr_id(i);
+        self.with_conn(i, |conn| {
+            let (addr, rkey) = (conn.remote_props.addr, conn.remote_props.rkey);
+            conn.post_send_wr(wr_id, opcode, len, addr, rkey)
+        })
+    }
+
+    fn post_read_index(&mut self, i: usize, offset: usize, len: usize) -> Result<u64> {
+        if offset + len > self.remote_index_size(i) {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "index read of {} bytes at {} is out of bounds",
+                len, offset
+            )));
+        }
+        let wr_id = self.device.next_wr_id(i);
+        self.with_conn(i, |conn| {
+            let (addr, rkey) = (conn.remote_props.index_addr, conn.remote_props.index_rkey);
+            conn.post_send_wr(
+                wr_id,
+                ibv_wr_opcode::IBV_WR_RDMA_READ,
+                len,
+                addr + offset as u64,
+                rkey,
+            )
+        })
+    }
+
+    fn post_counter_op(&mut self, i: usize, slot: usize, op: CounterOp) -> Result<u64> {
+        if slot >= self.remote_counter_slots(i) {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "counter slot {} is out of bounds",
+                slot
+            )));
+        }
+        let wr_id = self.device.next_wr_id(i);
+        self.with_conn(i, |conn| {
+            let remote_addr = conn.remote_props.counters_addr + (slot * 8) as u64;
+            let rkey = conn.remote_props.counters_rkey;
+            match op {
+                CounterOp::Read => {
+                    conn.post_send_wr(wr_id, ibv_wr_opcode::IBV_WR_RDMA_READ, 8, remote_addr, rkey)
+                }
+                CounterOp::FetchAdd(add) => conn.post_atomic(
+                    wr_id,
+                    ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD,
+                    remote_addr,
+                    rkey,
+                    add,
+                    0,
+                ),
+                CounterOp::CompareSwap { compare, swap } => conn.post_atomic(
+                    wr_id,
+                    ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP,
+                    remote_addr,
+                    rkey,
+                    compare,
+                    swap,
+                ),
+            }
+        })
+    }
+
+    fn post_receive(&mut self, i: usize) -> Result<u64> {
+        let wr_id = self.device.next_wr_id(i);
+        self.with_conn(i, |conn| conn.post_receive(wr_id))
+    }
+
+    fn poll_completion(&self) -> Result<Completion> {
+        loop {
+            if let Some(wc) = self.try_poll_completion()? {
+                return Ok(wc);
+            }
+            let Some(fd) = self.device.cq.channel_fd() else {
+                continue;
+            };
+            self.wait_comp_channel(fd, None)?;
+            self.device.cq.get_event()?;
+        }
+    }
+
+    fn poll_completion_timeout(&self, timeout: Duration) -> Result<Option<Completion>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(wc) = self.try_poll_completion()? {
+                return Ok(Some(wc));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            let Some(fd) = self.device.cq.channel_fd() else {
+                continue;
+            };
+            // no event is pending when the wait timed out, get_event says so
+            self.wait_comp_channel(fd, Some(left))?;
+            self.device.cq.get_event()?;
+        }
+    }
+
+    async fn poll_completion_async(&self) -> Result<Completion> {
+        loop {
+            if let Some(wc) = self.try_poll_completion()? {
+                return Ok(wc);
+            }
+            let Some(fd) = self.device.cq.channel_fd() else {
+                tokio::task::yield_now().await;
+                continue;
+            };
+            let comp_fd = self
+                .device
+                .comp_fd
+                .get_or_try_init(|| async { AsyncFd::new(fd) })
+                .await
+                .map_err(|e| RdmaKvError::resource("watching completion channel", e))?;
+            let mut guard = comp_fd
+                .readable()
+                .await
+                .map_err(|e| RdmaKvError::resource("watching completion channel", e))?;
+            if !self.device.cq.get_event()? {
+                guard.clear_ready();
+            }
+        }
+    }
+
+    fn read_buf(&self, i: usize) -> Vec<u8> {
+        self.with_conn(i, |conn| Ok(conn.mr.buf().clone()))
+            .unwrap_or_default()
+    }
+
+    fn write_buf(&mut self, bytes: &[u8], i: usize) {
+        let _ = self.with_conn(i, |conn| {
+            conn.mr.as_mut_slice()[..bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        });
+    }
+
+    fn index_size(&self) -> usize {
+        self.device.index.as_ref().map_or(0, |index| index.len())
+    }
+
+    fn write_index(&mut self, bytes: &[u8], offset: usize) -> Result<()> {
+        let region = self
+            .device
+            .index
+            .as_ref()
+            .and_then(|index| index.get(offset..offset + bytes.len()))
+            .ok_or_else(|| {
+                RdmaKvError::InvalidInput(format!(
+                    "index write of {} bytes at {} is out of bounds",
+                    bytes.len(),
+                    offset
+                ))
+            })?;
+        for (byte, &value) in region.iter().zip(bytes) {
+            byte.store(value, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn remote_index_size(&self, i: usize) -> usize {
+        self.with_conn(i, |conn| Ok(conn.remote_props.index_size as usize))
+            .unwrap_or(0)
+    }
+
+    fn counter_slots(&self) -> usize {
+        self.device
+            .counters
+            .as_ref()
+            .map_or(0, |counters| counters.len())
+    }
+
+    fn counter(&self, slot: usize) -> Result<&AtomicU64> {
+        self.device
+            .counters
+            .as_ref()
+            .and_then(|counters| counters.get(slot))
+            .ok_or_else(|| {
+                RdmaKvError::InvalidInput(format!("counter slot {} is out of bounds", slot))
+            })
+    }
+
+    fn remote_counter_slots(&self, i: usize) -> usize {
+        self.with_conn(i, |conn| Ok(conn.remote_props.counter_slots as usize))
+            .unwrap_or(0)
+    }
+
+    fn status(&self) -> TransportStatus {
+        self.device.status()
     }
 }
@@ -0,0 +1,158 @@
+use crate::error::Result;
+use crate::gid::Gid;
+use crate::verbs::{self, DeviceContext};
+use rdma_sys::ibv_port_attr;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, UdpSocket},
+};
+use tracing::{info, warn};
+
+const ROCE_V2: &str = "RoCE v2";
+
+// where the kernel describes the GIDs of a port, only RoCE ports have it
+fn gid_attr(device: &str, port: u8, attr: &str, index: i32) -> Option<String> {
+    let path = format!(
+        "/sys/class/infiniband/{}/ports/{}/gid_attrs/{}/{}",
+        device, port, attr, index
+    );
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// One populated entry of the GID table of a port.
+#[derive(Debug, Clone)]
+pub struct GidEntry {
+    pub index: i32,
+    pub gid: Gid,
+    /// `IB/RoCE v1` or `RoCE v2`, from sysfs.
+    pub gid_type: Option<String>,
+    /// The network interface a RoCE GID belongs to, from sysfs.
+    pub ndev: Option<String>,
+}
+
+/// The populated entries of the GID table of `port`.
+pub fn gid_table(ctx: &DeviceContext, port: u8, port_attr: &ibv_port_attr) -> Vec<GidEntry> {
+    let name = ctx.name();
+    (0..port_attr.gid_tbl_len)
+        .filter_map(|index| {
+            let gid = ctx.query_gid(port, index).ok()?;
+            (gid != Gid::default()).then(|| GidEntry {
+                index,
+                gid,
+                gid_type: gid_attr(&name, port, "types", index),
+                ndev: gid_attr(&name, port, "ndevs", index),
+            })
+        })
+        .collect()
+}
+
+// the local address packets to server leave from, as routed by the kernel.
+// Connecting a UDP socket sends nothing
+fn source_addr(server: &str) -> Option<Ipv4Addr> {
+    let server: IpAddr = server.parse().ok()?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((server, 9)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// The GID index `--gidx auto` stands for: the RoCE v2 IPv4 GID of the
+/// address `server` is reached from, else the first RoCE v2 IPv4 GID of the
+/// port. Index 0 when the port has none, an InfiniBand port for instance.
+pub fn auto_gid_index(
+    ctx: &DeviceContext,
+    port: u8,
+    port_attr: &ibv_port_attr,
+    server: Option<&str>,
+) -> i32 {
+    let roce_v2_ipv4: Vec<_> = gid_table(ctx, port, port_attr)
+        .into_iter()
+        .filter(|entry| entry.gid_type.as_deref() == Some(ROCE_V2))
+        .filter_map(|entry| entry.gid.to_ipv4().map(|addr| (addr, entry)))
+        .collect();
+    let source = server.and_then(source_addr);
+    let chosen = source
+        .and_then(|source| roce_v2_ipv4.iter().find(|(addr, _)| *addr == source))
+        .or_else(|| roce_v2_ipv4.first());
+    match chosen {
+        Some((addr, entry)) => {
+            info!(
+                "Using gid {} of port {}: {} on {}",
+                entry.index,
+                port,
+                addr,
+                entry.ndev.as_deref().unwrap_or("no interface")
+            );
+            if let Some(source) = source.filter(|source| source != addr) {
+                warn!(
+                    "The server is reached from {}, which port {} has no gid for",
+                    source, port
+                );
+            }
+            entry.index
+        }
+        None => {
+            info!("Port {} has no RoCE v2 IPv4 gid, using gid 0", port);
+            0
+        }
+    }
+}
+
+// the link layers of verbs.h, an anonymous enum that rdma-sys generates no
+// bindings for
+const IBV_LINK_LAYER_INFINIBAND: u8 = 1;
+const IBV_LINK_LAYER_ETHERNET: u8 = 2;
+
+fn link_layer_str(link_layer: u8) -> &'static str {
+    match link_layer {
+        IBV_LINK_LAYER_INFINIBAND => "InfiniBand",
+        IBV_LINK_LAYER_ETHERNET => "Ethernet",
+        _ => "unspecified",
+    }
+}
+
+/// Print every device, its ports and their GID tables, what `--ib-devname`,
+/// `--ib-port` and `--gidx` select.
+pub fn list() -> Result<()> {
+    let names = verbs::device_names()?;
+    if names.is_empty() {
+        println!("No RDMA devices found");
+    }
+    for name in names {
+        let ctx = DeviceContext::open(Some(&name))?;
+        let device_attr = ctx.query_device()?;
+        println!("{}", name);
+        for port in 1..=device_attr.phys_port_cnt {
+            let port_attr = match ctx.query_port(port) {
+                Ok(port_attr) => port_attr,
+                Err(e) => {
+                    println!("  port {}: {}", port, e);
+                    continue;
+                }
+            };
+            println!(
+                "  port {}: {}, MTU {} (max {}), LID {}, {}",
+                port,
+                verbs::port_state_str(port_attr.state),
+                verbs::mtu_bytes(port_attr.active_mtu),
+                verbs::mtu_bytes(port_attr.max_mtu),
+                port_attr.lid,
+                link_layer_str(port_attr.link_layer)
+            );
+            for entry in gid_table(&ctx, port, &port_attr) {
+                println!(
+                    "    gid {:>3}: {}  {:<10}  {}",
+                    entry.index,
+                    entry.gid,
+                    entry.gid_type.as_deref().unwrap_or("-"),
+                    entry.ndev.as_deref().unwrap_or("-")
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Where a `KvServer` keeps its values, picked with `--engine`.
+//!
+//! An engine only stores strings: the index the clients read and the
+//! counter slots stay with the server, which keeps them in sync with it.
+use crate::cli::EngineKind;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+};
+
+/// What an engine holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct EngineStats {
+    pub engine: &'static str,
+    pub keys: usize,
+    /// Bytes of the keys and values, without the engine's overhead.
+    pub bytes: usize,
+}
+
+/// A store of string values.
+pub trait KvEngine: Send {
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Returns the value key held.
+    fn set(&mut self, key: String, value: String) -> Option<String>;
+
+    /// Returns the value key held.
+    fn delete(&mut self, key: &str) -> Option<String>;
+
+    /// Up to `limit` entries from `from` on, in key order.
+    fn scan(&self, from: Bound<&str>, limit: usize) -> Vec<(String, String)>;
+
+    fn stats(&self) -> EngineStats;
+}
+
+/// The engine of `kind`, empty.
+pub fn new(kind: EngineKind) -> Box<dyn KvEngine> {
+    match kind {
+        EngineKind::Hash => Box::<HashEngine>::default(),
+        EngineKind::Btree => Box::<BTreeEngine>::default(),
+    }
+}
+
+/// Whether `key` is past the start of a scan from `from`.
+pub fn after(from: Bound<&str>, key: &str) -> bool {
+    match from {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+// the bytes an entry adds to EngineStats::bytes
+fn entry_bytes(key: &str, value: &str) -> usize {
+    key.len() + value.len()
+}
+
+/// Constant time gets and sets, scans sort the keys they return.
+#[derive(Debug, Default)]
+pub struct HashEngine {
+    map: HashMap<String, String>,
+    // kept up to date, stats would walk the map otherwise
+    bytes: usize,
+}
+
+impl KvEngine for HashEngine {
+    fn get(&self, key: &str) -> Option<String> {
+        self.map.get(key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Option<String> {
+        if let Some(old) = self.map.get(&key) {
+            self.bytes -= entry_bytes(&key, old);
+        }
+        self.bytes += entry_bytes(&key, &value);
+        self.map.insert(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Option<String> {
+        let old = self.map.remove(key);
+        if let Some(old) = &old {
+            self.bytes -= entry_bytes(key, old);
+        }
+        old
+    }
+
+    fn scan(&self, from: Bound<&str>, limit: usize) -> Vec<(String, String)> {
+        let mut entries: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(key, _)| after(from, key))
+            .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+            .into_iter()
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            engine: "hash",
+            keys: self.map.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+/// Keys kept in order, scans walk them without sorting.
+#[derive(Debug, Default)]
+pub struct BTreeEngine {
+    map: BTreeMap<String, String>,
+    // kept up to date, stats would walk the map otherwise
+    bytes: usize,
+}
+
+impl KvEngine for BTreeEngine {
+    fn get(&self, key: &str) -> Option<String> {
+        self.map.get(key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Option<String> {
+        if let Some(old) = self.map.get(&key) {
+            self.bytes -= entry_bytes(&key, old);
+        }
+        self.bytes += entry_bytes(&key, &value);
+        self.map.insert(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Option<String> {
+        let old = self.map.remove(key);
+        if let Some(old) = &old {
+            self.bytes -= entry_bytes(key, old);
+        }
+        old
+    }
+
+    fn scan(&self, from: Bound<&str>, limit: usize) -> Vec<(String, String)> {
+        self.map
+            .range::<str, _>((from, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            engine: "btree",
+            keys: self.map.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every test runs against both engines
+    fn engines() -> [Box<dyn KvEngine>; 2] {
+        [new(EngineKind::Hash), new(EngineKind::Btree)]
+    }
+
+    fn filled(keys: &[&str]) -> [Box<dyn KvEngine>; 2] {
+        let mut engines = engines();
+        for engine in &mut engines {
+            for key in keys {
+                engine.set(key.to_string(), format!("v{}", key));
+            }
+        }
+        engines
+    }
+
+    fn keys(entries: Vec<(String, String)>) -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    // the value set, None deletes, then what the key held and what a get sees
+    type Step = (
+        Option<&'static str>,
+        Option<&'static str>,
+        Option<&'static str>,
+    );
+
+    #[test]
+    fn get_set_delete() {
+        let steps: [Step; 4] = [
+            (Some("1"), None, Some("1")),
+            (Some("2"), Some("1"), Some("2")),
+            (None, Some("2"), None),
+            (None, None, None),
+        ];
+        for mut engine in engines() {
+            for (value, held, seen) in steps {
+                let old = match value {
+                    Some(value) => engine.set("a".to_string(), value.to_string()),
+                    None => engine.delete("a"),
+                };
+                let name = engine.stats().engine;
+                assert_eq!(old.as_deref(), held, "{}", name);
+                assert_eq!(engine.get("a").as_deref(), seen, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn scan_order_and_bounds() {
+        let cases: [(Bound<&str>, usize, &[&str]); 5] = [
+            (Bound::Unbounded, 10, &["a", "ab", "b", "c", "d"]),
+            (Bound::Unbounded, 2, &["a", "ab"]),
+            (Bound::Included("b"), 10, &["b", "c", "d"]),
+            (Bound::Excluded("b"), 10, &["c", "d"]),
+            (Bound::Excluded("bb"), 1, &["c"]),
+        ];
+        for engine in filled(&["d", "b", "ab", "c", "a"]) {
+            for (from, limit, expected) in cases {
+                assert_eq!(
+                    keys(engine.scan(from, limit)),
+                    expected,
+                    "{} from {:?}",
+                    engine.stats().engine,
+                    from
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scan_resumes_after_the_cursor() {
+        let all: Vec<_> = (0..25).map(|i| format!("k{:02}", i)).collect();
+        let refs: Vec<_> = all.iter().map(String::as_str).collect();
+        for engine in filled(&refs) {
+            let mut seen = Vec::new();
+            let mut from = Bound::Unbounded;
+            let mut cursor;
+            loop {
+                let page = keys(engine.scan(from, 7));
+                if page.is_empty() {
+                    break;
+                }
+                seen.extend(page.iter().cloned());
+                cursor = page.last().unwrap().clone();
+                from = Bound::Excluded(&cursor);
+            }
+            assert_eq!(seen, all, "{}", engine.stats().engine);
+        }
+    }
+
+    #[test]
+    fn stats() {
+        for (mut engine, name) in engines().into_iter().zip(["hash", "btree"]) {
+            engine.set("ab".to_string(), "cde".to_string());
+            engine.set("f".to_string(), "".to_string());
+            let stats = engine.stats();
+            assert_eq!((stats.engine, stats.keys, stats.bytes), (name, 2, 6));
+            engine.set("f".to_string(), "gh".to_string());
+            engine.delete("ab");
+            let stats = engine.stats();
+            assert_eq!((stats.keys, stats.bytes), (1, 3));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use rdma_sys::ibv_wc_status;
+use std::{fmt, io};
+
+/// Everything that can go wrong in the RDMA and KV stack.
+#[derive(Debug)]
+pub enum RdmaKvError {
+    /// No device matched, or it could not be opened or queried.
+    Device { context: String, source: io::Error },
+    /// A verbs object could not be created, registered or changed, such as a
+    /// QP that refused a state transition.
+    Resource {
+        context: &'static str,
+        source: io::Error,
+    },
+    /// Reaching or talking to the peer failed, or the connection is gone.
+    Connection { context: String, source: io::Error },
+    /// The peer sent something that does not follow the protocol.
+    Protocol(String),
+    /// A work request completed with an error status.
+    Completion {
+        qp: usize,
+        wr_id: u64,
+        status: ibv_wc_status::Type,
+    },
+    /// An option or an argument is out of range.
+    InvalidInput(String),
+    /// The store refused the operation, the message comes from the server.
+    Store(String),
+    /// The files the server keeps the store in could not be read or
+    /// written.
+    Storage { context: String, source: io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, RdmaKvError>;
+
+impl RdmaKvError {
+    pub fn device(context: impl Into<String>, source: io::Error) -> Self {
+        RdmaKvError::Device {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn resource(context: &'static str, source: io::Error) -> Self {
+        RdmaKvError::Resource { context, source }
+    }
+
+    pub fn connection(context: impl Into<String>, source: io::Error) -> Self {
+        RdmaKvError::Connection {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn storage(context: impl Into<String>, source: io::Error) -> Self {
+        RdmaKvError::Storage {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Connection `i` was dropped, or never existed.
+    pub fn not_connected(i: usize) -> Self {
+        Self::connection(
+            format!("connection {} is gone", i),
+            io::ErrorKind::NotConnected.into(),
+        )
+    }
+
+    /// Whether the connection the error happened on is unusable until it is
+    /// brought up again: a work request failed, which leaves the QP in the
+    /// error state, a post was refused or the peer is gone.
+    pub fn breaks_connection(&self) -> bool {
+        matches!(
+            self,
+            RdmaKvError::Completion { .. }
+                | RdmaKvError::Connection { .. }
+                | RdmaKvError::Resource { .. }
+        )
+    }
+}
+
+impl fmt::Display for RdmaKvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdmaKvError::Device { context, source } => {
+                write!(f, "device error: {}: {}", context, source)
+            }
+            RdmaKvError::Resource { context, source } => {
+                write!(f, "resource error: {}: {}", context, source)
+            }
+            RdmaKvError::Connection { context, source } => {
+                write!(f, "connection error: {}: {}", context, source)
+            }
+            RdmaKvError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            RdmaKvError::Completion { qp, wr_id, status } => write!(
+                f,
+                "WC Failed on qp {}, status: {:#0X}, wr_id: {:#0X}",
+                qp, status, wr_id
+            ),
+            RdmaKvError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            RdmaKvError::Store(msg) => write!(f, "store error: {}", msg),
+            RdmaKvError::Storage { context, source } => {
+                write!(f, "storage error: {}: {}", context, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RdmaKvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RdmaKvError::Device { source, .. }
+            | RdmaKvError::Resource { source, .. }
+            | RdmaKvError::Connection { source, .. }
+            | RdmaKvError::Storage { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// socket errors, the only io errors left once the verbs calls are mapped
+impl From<io::Error> for RdmaKvError {
+    fn from(source: io::Error) -> Self {
+        Self::connection("socket", source)
+    }
+}
+
+impl From<serde_json::Error> for RdmaKvError {
+    fn from(e: serde_json::Error) -> Self {
+        RdmaKvError::Protocol(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for RdmaKvError {
+    fn from(e: bincode::Error) -> Self {
+        RdmaKvError::Protocol(e.to_string())
+    }
+}
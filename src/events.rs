@@ -0,0 +1,218 @@
+use crate::verbs::AsyncEvent;
+use rdma_sys::ibv_event_type;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// how many events the status keeps, the oldest ones are dropped first
+const EVENT_HISTORY: usize = 64;
+
+/// An async event of the device, as reported by `ibv_get_async_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RdmaEvent {
+    PortActive {
+        port: u8,
+    },
+    PortError {
+        port: u8,
+    },
+    LidChange {
+        port: u8,
+    },
+    PkeyChange {
+        port: u8,
+    },
+    GidChange {
+        port: u8,
+    },
+    SmChange {
+        port: u8,
+    },
+    ClientReregister {
+        port: u8,
+    },
+    /// The QP hit an error it cannot report through a completion, it is in
+    /// the error state.
+    QpFatal {
+        qp_num: u32,
+    },
+    /// The peer sent a request the QP could not make sense of.
+    QpRequestError {
+        qp_num: u32,
+    },
+    /// The peer violated the access rights of the QP or of an MR.
+    QpAccessError {
+        qp_num: u32,
+    },
+    CommEstablished {
+        qp_num: u32,
+    },
+    SqDrained {
+        qp_num: u32,
+    },
+    PathMigrated {
+        qp_num: u32,
+    },
+    PathMigrationError {
+        qp_num: u32,
+    },
+    LastWqeReached {
+        qp_num: u32,
+    },
+    CqError,
+    SrqError,
+    SrqLimitReached,
+    /// The device is unusable, every QP on it is lost.
+    DeviceFatal,
+    Other(ibv_event_type::Type),
+}
+
+impl RdmaEvent {
+    /// The QP the event leaves in the error state, if any.
+    pub fn failed_qp(&self) -> Option<u32> {
+        match *self {
+            RdmaEvent::QpFatal { qp_num }
+            | RdmaEvent::QpRequestError { qp_num }
+            | RdmaEvent::QpAccessError { qp_num }
+            | RdmaEvent::PathMigrationError { qp_num } => Some(qp_num),
+            _ => None,
+        }
+    }
+
+    /// Whether traffic stops because of the event.
+    pub fn is_fatal(&self) -> bool {
+        self.failed_qp().is_some()
+            || matches!(
+                self,
+                RdmaEvent::PortError { .. }
+                    | RdmaEvent::CqError
+                    | RdmaEvent::SrqError
+                    | RdmaEvent::DeviceFatal
+            )
+    }
+}
+
+impl From<AsyncEvent> for RdmaEvent {
+    fn from(event: AsyncEvent) -> Self {
+        let port = event.port.unwrap_or_default();
+        let qp_num = event.qp_num.unwrap_or_default();
+        match event.event_type {
+            ibv_event_type::IBV_EVENT_PORT_ACTIVE => RdmaEvent::PortActive { port },
+            ibv_event_type::IBV_EVENT_PORT_ERR => RdmaEvent::PortError { port },
+            ibv_event_type::IBV_EVENT_LID_CHANGE => RdmaEvent::LidChange { port },
+            ibv_event_type::IBV_EVENT_PKEY_CHANGE => RdmaEvent::PkeyChange { port },
+            ibv_event_type::IBV_EVENT_GID_CHANGE => RdmaEvent::GidChange { port },
+            ibv_event_type::IBV_EVENT_SM_CHANGE => RdmaEvent::SmChange { port },
+            ibv_event_type::IBV_EVENT_CLIENT_REREGISTER => RdmaEvent::ClientReregister { port },
+            ibv_event_type::IBV_EVENT_QP_FATAL => RdmaEvent::QpFatal { qp_num },
+            ibv_event_type::IBV_EVENT_QP_REQ_ERR => RdmaEvent::QpRequestError { qp_num },
+            ibv_event_type::IBV_EVENT_QP_ACCESS_ERR => RdmaEvent::QpAccessError { qp_num },
+            ibv_event_type::IBV_EVENT_COMM_EST => RdmaEvent::CommEstablished { qp_num },
+            ibv_event_type::IBV_EVENT_SQ_DRAINED => RdmaEvent::SqDrained { qp_num },
+            ibv_event_type::IBV_EVENT_PATH_MIG => RdmaEvent::PathMigrated { qp_num },
+            ibv_event_type::IBV_EVENT_PATH_MIG_ERR => RdmaEvent::PathMigrationError { qp_num },
+            ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED => RdmaEvent::LastWqeReached { qp_num },
+            ibv_event_type::IBV_EVENT_CQ_ERR => RdmaEvent::CqError,
+            ibv_event_type::IBV_EVENT_SRQ_ERR => RdmaEvent::SrqError,
+            ibv_event_type::IBV_EVENT_SRQ_LIMIT_REACHED => RdmaEvent::SrqLimitReached,
+            ibv_event_type::IBV_EVENT_DEVICE_FATAL => RdmaEvent::DeviceFatal,
+            other => RdmaEvent::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for RdmaEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdmaEvent::PortActive { port } => write!(f, "port {} is active", port),
+            RdmaEvent::PortError { port } => write!(f, "port {} went down", port),
+            RdmaEvent::LidChange { port } => write!(f, "LID of port {} changed", port),
+            RdmaEvent::PkeyChange { port } => write!(f, "P_Key table of port {} changed", port),
+            RdmaEvent::GidChange { port } => write!(f, "GID table of port {} changed", port),
+            RdmaEvent::SmChange { port } => write!(f, "SM of port {} changed", port),
+            RdmaEvent::ClientReregister { port } => {
+                write!(f, "SM asked port {} to reregister", port)
+            }
+            RdmaEvent::QpFatal { qp_num } => write!(f, "QP {} hit a fatal error", qp_num),
+            RdmaEvent::QpRequestError { qp_num } => {
+                write!(f, "QP {} got an invalid request", qp_num)
+            }
+            RdmaEvent::QpAccessError { qp_num } => {
+                write!(f, "QP {} got an access violation", qp_num)
+            }
+            RdmaEvent::CommEstablished { qp_num } => {
+                write!(f, "QP {} established communication", qp_num)
+            }
+            RdmaEvent::SqDrained { qp_num } => write!(f, "send queue of QP {} drained", qp_num),
+            RdmaEvent::PathMigrated { qp_num } => write!(f, "QP {} migrated its path", qp_num),
+            RdmaEvent::PathMigrationError { qp_num } => {
+                write!(f, "QP {} failed to migrate its path", qp_num)
+            }
+            RdmaEvent::LastWqeReached { qp_num } => {
+                write!(f, "QP {} reached its last WQE", qp_num)
+            }
+            RdmaEvent::CqError => write!(f, "CQ overrun"),
+            RdmaEvent::SrqError => write!(f, "SRQ error"),
+            RdmaEvent::SrqLimitReached => write!(f, "SRQ limit reached"),
+            RdmaEvent::DeviceFatal => write!(f, "device hit a fatal error"),
+            RdmaEvent::Other(event_type) => write!(f, "unknown event {}", event_type),
+        }
+    }
+}
+
+/// An event as kept for the status.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    /// Seconds since the epoch when the event was read.
+    pub time: u64,
+    pub fatal: bool,
+    pub event: String,
+}
+
+/// The last events of a device, newest last.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: VecDeque<EventRecord>,
+}
+
+impl EventLog {
+    pub fn push(&mut self, event: &RdmaEvent) {
+        if self.events.len() == EVENT_HISTORY {
+            self.events.pop_front();
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.events.push_back(EventRecord {
+            time,
+            fatal: event.is_fatal(),
+            event: event.to_string(),
+        });
+    }
+
+    pub fn records(&self) -> Vec<EventRecord> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+/// Health of one connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub id: usize,
+    /// Number of the local QP, `None` for a transport without QPs.
+    pub qp_num: Option<u32>,
+    pub healthy: bool,
+    /// Why the connection is unhealthy.
+    pub reason: Option<String>,
+}
+
+/// What an operator needs to see why traffic stopped.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransportStatus {
+    pub port_active: bool,
+    pub connections: Vec<ConnectionStatus>,
+    pub events: Vec<EventRecord>,
+}
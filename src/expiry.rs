@@ -0,0 +1,113 @@
+//! When the keys with a TTL expire.
+//!
+//! Deadlines are milliseconds since the UNIX epoch, so that they mean the
+//! same after a restart. They are kept by key and in a queue ordered by
+//! deadline, a sweep pops the expired ones off its front.
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Milliseconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The deadlines of the keys that expire.
+#[derive(Debug, Default)]
+pub struct Expiry {
+    deadlines: HashMap<String, u64>,
+    // the same deadlines, soonest first
+    queue: BTreeSet<(u64, String)>,
+}
+
+impl Expiry {
+    /// Let `key` expire at `deadline`, instead of when it did.
+    pub fn set(&mut self, key: &str, deadline: u64) {
+        if let Some(old) = self.deadlines.insert(key.to_string(), deadline) {
+            self.queue.remove(&(old, key.to_string()));
+        }
+        self.queue.insert((deadline, key.to_string()));
+    }
+
+    /// Keep `key` from expiring, returns whether it was going to.
+    pub fn clear(&mut self, key: &str) -> bool {
+        match self.deadlines.remove(key) {
+            Some(deadline) => self.queue.remove(&(deadline, key.to_string())),
+            None => false,
+        }
+    }
+
+    pub fn deadline(&self, key: &str) -> Option<u64> {
+        self.deadlines.get(key).copied()
+    }
+
+    /// Whether `key` has expired at `now`.
+    pub fn expired(&self, key: &str, now: u64) -> bool {
+        self.deadline(key).is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Forget up to `limit` keys that have expired at `now`, soonest first,
+    /// and return them.
+    pub fn pop_expired(&mut self, now: u64, limit: usize) -> Vec<String> {
+        let mut keys = Vec::new();
+        while keys.len() < limit {
+            match self.queue.first() {
+                Some((deadline, _)) if *deadline <= now => {
+                    let (_, key) = self.queue.pop_first().unwrap();
+                    self.deadlines.remove(&key);
+                    keys.push(key);
+                }
+                _ => break,
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_clear() {
+        let mut expiry = Expiry::default();
+        assert!(!expiry.clear("a"));
+        expiry.set("a", 100);
+        assert_eq!(expiry.deadline("a"), Some(100));
+        assert!(!expiry.expired("a", 99));
+        assert!(expiry.expired("a", 100));
+        assert!(!expiry.expired("b", u64::MAX));
+        // a new deadline replaces the old one, also in the queue
+        expiry.set("a", 200);
+        assert_eq!(expiry.deadline("a"), Some(200));
+        assert!(expiry.pop_expired(150, 10).is_empty());
+        assert!(expiry.clear("a"));
+        assert_eq!(expiry.deadline("a"), None);
+        assert!(expiry.pop_expired(u64::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn pop_expired_soonest_first() {
+        let mut expiry = Expiry::default();
+        for (key, deadline) in [("c", 30), ("a", 10), ("d", 40), ("b", 20), ("b2", 20)] {
+            expiry.set(key, deadline);
+        }
+        assert_eq!(expiry.pop_expired(5, 10), Vec::<String>::new());
+        assert_eq!(expiry.pop_expired(25, 2), ["a", "b"]);
+        assert_eq!(expiry.deadline("a"), None);
+        assert_eq!(expiry.deadline("b2"), Some(20));
+        assert_eq!(expiry.pop_expired(25, 0), Vec::<String>::new());
+        assert_eq!(expiry.pop_expired(30, 10), ["b2", "c"]);
+        assert_eq!(expiry.deadline("d"), Some(40));
+    }
+
+    #[test]
+    fn now_moves_on() {
+        let before = now();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(now() > before);
+    }
+}
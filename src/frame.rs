@@ -0,0 +1,90 @@
+use crate::error::{RdmaKvError, Result};
+
+/// Buffer size used when `--buf-size` is not given.
+pub const DEFAULT_BUF_SIZE: usize = 4096;
+
+/// Every message starts with its payload length as a little endian u32.
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Frame `payload` for a buffer of `buf_size` bytes.
+///
+/// The result holds only the header and the payload, payloads that do not fit
+/// are rejected instead of being truncated.
+pub fn encode(payload: &[u8], buf_size: usize) -> Result<Vec<u8>> {
+    let max_payload = buf_size.saturating_sub(FRAME_HEADER_SIZE);
+    // a buffer too small for the header cannot carry even an empty payload
+    if FRAME_HEADER_SIZE + payload.len() > buf_size {
+        return Err(RdmaKvError::InvalidInput(format!(
+            "message of {} bytes exceeds the {} bytes a {} byte buffer can carry",
+            payload.len(),
+            max_payload,
+            buf_size
+        )));
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Return the payload of the frame at the start of `buf`.
+pub fn decode(buf: &[u8]) -> Result<&[u8]> {
+    if buf.len() < FRAME_HEADER_SIZE {
+        return Err(RdmaKvError::Protocol(format!(
+            "buffer of {} bytes cannot hold a frame header",
+            buf.len()
+        )));
+    }
+    let (header, rest) = buf.split_at(FRAME_HEADER_SIZE);
+    let len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+    rest.get(..len).ok_or_else(|| {
+        RdmaKvError::Protocol(format!(
+            "frame claims {} bytes but the buffer only has {}",
+            len,
+            rest.len()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUF_SIZE: usize = 64;
+
+    #[test]
+    fn largest_payload_fits() {
+        let payload = vec![7; BUF_SIZE - FRAME_HEADER_SIZE];
+        let frame = encode(&payload, BUF_SIZE).unwrap();
+        assert_eq!(frame.len(), BUF_SIZE);
+        assert_eq!(decode(&frame).unwrap(), payload);
+    }
+
+    #[test]
+    fn one_byte_over_is_rejected() {
+        let payload = vec![7; BUF_SIZE - FRAME_HEADER_SIZE + 1];
+        let err = encode(&payload, BUF_SIZE).unwrap_err();
+        assert!(matches!(&err, RdmaKvError::InvalidInput(_)), "{:?}", err);
+        assert!(err.to_string().contains("61 bytes"), "{}", err);
+        assert!(encode(b"", FRAME_HEADER_SIZE - 1).is_err());
+    }
+
+    #[test]
+    fn decode_ignores_what_follows() {
+        let mut buf = encode(b"abc", BUF_SIZE).unwrap();
+        buf.resize(BUF_SIZE, 0xff);
+        assert_eq!(decode(&buf).unwrap(), b"abc");
+        assert_eq!(decode(&[0; FRAME_HEADER_SIZE]).unwrap(), b"");
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut buf = vec![0; BUF_SIZE];
+        buf[..FRAME_HEADER_SIZE].copy_from_slice(&(BUF_SIZE as u32).to_le_bytes());
+        let cases: [&[u8]; 3] = [&buf, &buf[..FRAME_HEADER_SIZE - 1], &[]];
+        for buf in cases {
+            let err = decode(buf).unwrap_err();
+            assert!(matches!(err, RdmaKvError::Protocol(_)), "{:?}", err);
+        }
+    }
+}
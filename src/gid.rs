@@ -1,5 +1,10 @@
 use rdma_sys::*;
 use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 /// A Global identifier for ibv.
 ///
 /// This struct acts as a rust wrapper for `ffi::ibv_gid`. We use it instead of
@@ -19,7 +24,11 @@ use serde::{Deserialize, Serialize};
 /// For continuity, the methods `subnet_prefix` and `interface_id` are provided.
 /// These methods read the array as big endian, regardless of native cpu
 /// endianness.
-#[derive(Default, Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+///
+/// A GID has the layout of an IPv6 address, RoCE derives it from an address
+/// of the interface: IPv4 addresses are mapped as `::ffff:a.b.c.d`. It is
+/// written the way IPv6 addresses are, see `Display` and `FromStr`.
+#[derive(Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Gid {
     raw: [u8; 16],
 }
@@ -46,6 +55,74 @@ impl Gid {
     pub fn interface_id(&self) -> u64 {
         u64::from_be_bytes(self.raw[8..].try_into().unwrap())
     }
+
+    /// The IPv4 address of an IPv4-mapped GID, `::ffff:a.b.c.d`, the form
+    /// RoCE gives the addresses of IPv4 interfaces.
+    pub fn to_ipv4(self) -> Option<Ipv4Addr> {
+        Ipv6Addr::from(self).to_ipv4_mapped()
+    }
+
+    /// Whether the GID is in `fe80::/10`. The default GID of an IB port
+    /// and the RoCE v1 GIDs are, they do not leave the subnet.
+    pub fn is_link_local(&self) -> bool {
+        self.raw[0] == 0xfe && self.raw[1] & 0xc0 == 0x80
+    }
+
+    /// Whether the GID is routable, neither link-local nor unset.
+    pub fn is_global(&self) -> bool {
+        !self.is_link_local() && *self != Gid::default()
+    }
+}
+
+/// Eight colon-separated groups of four hex digits, the form `show_gids`
+/// and sysfs use.
+impl fmt::Display for Gid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, group) in self.raw.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}{:02x}", group[0], group[1])?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Gid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gid({})", self)
+    }
+}
+
+/// Anything an IPv6 address parses from, the full form printed by
+/// `Display` as well as `fe80::1` or `::ffff:192.168.1.2`.
+impl FromStr for Gid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ipv6Addr::from_str(s)
+            .map(Gid::from)
+            .map_err(|_| format!("{} is not a gid", s))
+    }
+}
+
+impl From<Ipv6Addr> for Gid {
+    fn from(addr: Ipv6Addr) -> Self {
+        Self { raw: addr.octets() }
+    }
+}
+
+impl From<Gid> for Ipv6Addr {
+    fn from(gid: Gid) -> Self {
+        Ipv6Addr::from(gid.raw)
+    }
+}
+
+/// The IPv4-mapped GID of `addr`.
+impl From<Ipv4Addr> for Gid {
+    fn from(addr: Ipv4Addr) -> Self {
+        addr.to_ipv6_mapped().into()
+    }
 }
 
 impl From<ibv_gid> for Gid {
@@ -61,3 +138,76 @@ impl From<Gid> for ibv_gid {
         unsafe { *gid.ffi() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gid(s: &str) -> Gid {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let cases = [
+            ("fe80::1", "fe80:0000:0000:0000:0000:0000:0000:0001"),
+            (
+                "::ffff:192.168.1.2",
+                "0000:0000:0000:0000:0000:ffff:c0a8:0102",
+            ),
+            ("::", "0000:0000:0000:0000:0000:0000:0000:0000"),
+        ];
+        for (short, full) in cases {
+            assert_eq!(gid(short).to_string(), full);
+            assert_eq!(gid(full), gid(short));
+            assert_eq!(format!("{:?}", gid(short)), format!("Gid({})", full));
+        }
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let addr = Ipv4Addr::new(10, 0, 0, 7);
+        assert_eq!(Gid::from(addr), gid("::ffff:10.0.0.7"));
+        assert_eq!(Gid::from(addr).to_ipv4(), Some(addr));
+        assert_eq!(gid("fe80::a00:7").to_ipv4(), None);
+        assert_eq!(gid("::a00:7").to_ipv4(), None);
+    }
+
+    #[test]
+    fn scope() {
+        // link-local, global
+        let cases = [
+            ("fe80::1", true, false),
+            ("febf::1", true, false),
+            ("fec0::1", false, true),
+            ("::ffff:10.0.0.7", false, true),
+            ("2001:db8::1", false, true),
+            ("::", false, false),
+        ];
+        for (s, link_local, global) in cases {
+            assert_eq!(gid(s).is_link_local(), link_local, "{}", s);
+            assert_eq!(gid(s).is_global(), global, "{}", s);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for s in [
+            "",
+            "fe80",
+            "fe80::1::2",
+            "10.0.0.7",
+            "fe80::g",
+            "1:2:3:4:5:6:7:8:9",
+        ] {
+            assert_eq!(s.parse::<Gid>(), Err(format!("{} is not a gid", s)));
+        }
+    }
+
+    #[test]
+    fn halves() {
+        let gid = gid("fe80::1:2:3:4");
+        assert_eq!(gid.subnet_prefix(), 0xfe80_0000_0000_0000);
+        assert_eq!(gid.interface_id(), 0x0001_0002_0003_0004);
+    }
+}
@@ -0,0 +1,326 @@
+//! The messages peers exchange before their QPs are connected.
+//!
+//! Every message starts with a fixed header: `MAGIC`, the protocol version
+//! of the sender and the length of the bincode payload that follows, both
+//! big endian. Payloads use varints, a peer ignores the fields a newer
+//! version appends. Both sides send what they support and settle on the
+//! intersection, see `Capabilities::negotiate`.
+use crate::error::{RdmaKvError, Result};
+use crate::frame::FRAME_HEADER_SIZE;
+use bincode::Options;
+use rdma_sys::ibv_mtu;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::Read;
+
+/// "RKVS", what tells an rdma-kv peer from anything else on the port.
+pub const MAGIC: u32 = 0x524b_5653;
+/// Version of the protocol spoken here. 2 admits clients before the conn
+/// data, see the auth module.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest version a peer may speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// Size of the header of a message.
+pub const HEADER_SIZE: usize = 8;
+// no message comes close, a larger one is not from a peer
+const MAX_PAYLOAD: usize = 4096;
+
+/// RDMA writes with immediate, which carry every request and reply.
+pub const OP_WRITE_WITH_IMM: u32 = 1 << 0;
+/// RDMA reads, of the index and of the counters.
+pub const OP_RDMA_READ: u32 = 1 << 1;
+/// Fetch-and-add and compare-and-swap.
+pub const OP_ATOMIC: u32 = 1 << 2;
+/// What a peer cannot do without.
+pub const REQUIRED_OPS: u32 = OP_WRITE_WITH_IMM;
+
+/// Gets served from the server's index with RDMA reads alone.
+pub const FEATURE_INDEX: u32 = 1 << 0;
+/// Counters changed with RDMA atomics.
+pub const FEATURE_COUNTERS: u32 = 1 << 1;
+/// Every feature this version knows of.
+pub const ALL_FEATURES: u32 = FEATURE_INDEX | FEATURE_COUNTERS;
+
+/// What a side supports.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Capabilities {
+    /// `OP_*` bits.
+    pub opcodes: u32,
+    /// `FEATURE_*` bits, a server offers what it exposes.
+    pub features: u32,
+    /// Size of the registered buffer.
+    pub buf_size: u32,
+    /// Work requests each queue of the QP holds. Not negotiated: the QP is
+    /// created before the peer is known, to send it the QP number.
+    pub queue_depth: u32,
+    /// Largest path MTU this side accepts, an `ibv_mtu`.
+    pub mtu: ibv_mtu::Type,
+}
+
+/// What both sides use.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub version: u16,
+    pub opcodes: u32,
+    pub features: u32,
+    pub buf_size: u32,
+    pub mtu: ibv_mtu::Type,
+}
+
+impl Negotiated {
+    /// Whether both sides can use `feature`, and the opcodes it needs.
+    pub fn has(&self, feature: u32) -> bool {
+        let ops = match feature {
+            FEATURE_INDEX => OP_RDMA_READ,
+            FEATURE_COUNTERS => OP_RDMA_READ | OP_ATOMIC,
+            _ => 0,
+        };
+        self.features & feature == feature && self.opcodes & ops == ops
+    }
+}
+
+impl Capabilities {
+    /// The intersection with what a peer speaking `peer_version` supports,
+    /// fails when the two cannot talk.
+    pub fn negotiate(&self, peer: &Capabilities, peer_version: u16) -> Result<Negotiated> {
+        let version = PROTOCOL_VERSION.min(peer_version);
+        let opcodes = self.opcodes & peer.opcodes;
+        if opcodes & REQUIRED_OPS != REQUIRED_OPS {
+            return Err(RdmaKvError::Protocol(format!(
+                "peer supports opcodes {:#x}, {:#x} are needed",
+                peer.opcodes, REQUIRED_OPS
+            )));
+        }
+        // never write past the end of the smaller buffer
+        let buf_size = self.buf_size.min(peer.buf_size);
+        if buf_size as usize <= FRAME_HEADER_SIZE {
+            return Err(RdmaKvError::Protocol(format!(
+                "peer buffer size {} is too small",
+                peer.buf_size
+            )));
+        }
+        if !(ibv_mtu::IBV_MTU_256..=ibv_mtu::IBV_MTU_4096).contains(&peer.mtu) {
+            return Err(RdmaKvError::Protocol(format!(
+                "peer MTU {} is invalid",
+                peer.mtu
+            )));
+        }
+        Ok(Negotiated {
+            version,
+            opcodes,
+            features: self.features & peer.features,
+            buf_size,
+            mtu: self.mtu.min(peer.mtu),
+        })
+    }
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().allow_trailing_bytes()
+}
+
+/// `msg` behind the header.
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let payload = options().serialize(msg)?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&MAGIC.to_be_bytes());
+    bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+// the version and the payload length of a header
+fn parse_header(header: &[u8]) -> Result<(u16, usize)> {
+    if header.len() < HEADER_SIZE {
+        return Err(RdmaKvError::Protocol(format!(
+            "handshake of {} bytes is too short",
+            header.len()
+        )));
+    }
+    let magic = u32::from_be_bytes(header[..4].try_into().unwrap());
+    if magic != MAGIC {
+        return Err(RdmaKvError::Protocol(format!(
+            "peer is not an rdma-kv peer, its handshake starts with {:#010x}",
+            magic
+        )));
+    }
+    let version = u16::from_be_bytes(header[4..6].try_into().unwrap());
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(RdmaKvError::Protocol(format!(
+            "peer speaks protocol version {}, at least {} is needed",
+            version, MIN_PROTOCOL_VERSION
+        )));
+    }
+    let len = u16::from_be_bytes(header[6..8].try_into().unwrap()) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(RdmaKvError::Protocol(format!(
+            "handshake of {} bytes is too long",
+            len
+        )));
+    }
+    Ok((version, len))
+}
+
+/// The version of the sender and the message in `bytes`, anything after
+/// it is ignored.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(u16, T)> {
+    let (version, len) = parse_header(bytes)?;
+    let payload = bytes.get(HEADER_SIZE..HEADER_SIZE + len).ok_or_else(|| {
+        RdmaKvError::Protocol(format!("handshake of {} bytes is cut short", bytes.len()))
+    })?;
+    Ok((version, options().deserialize(payload)?))
+}
+
+/// Read one message from `stream`.
+pub fn read<T: DeserializeOwned>(stream: &mut impl Read) -> Result<(u16, T)> {
+    let mut bytes = vec![0; HEADER_SIZE];
+    stream
+        .read_exact(&mut bytes)
+        .map_err(|e| RdmaKvError::connection("receiving conn data", e))?;
+    let (_, len) = parse_header(&bytes)?;
+    bytes.resize(HEADER_SIZE + len, 0);
+    stream
+        .read_exact(&mut bytes[HEADER_SIZE..])
+        .map_err(|e| RdmaKvError::connection("receiving conn data", e))?;
+    decode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(opcodes: u32, features: u32, buf_size: u32, mtu: ibv_mtu::Type) -> Capabilities {
+        Capabilities {
+            opcodes,
+            features,
+            buf_size,
+            queue_depth: 16,
+            mtu,
+        }
+    }
+
+    fn full() -> Capabilities {
+        caps(
+            OP_WRITE_WITH_IMM | OP_RDMA_READ | OP_ATOMIC,
+            ALL_FEATURES,
+            4096,
+            ibv_mtu::IBV_MTU_4096,
+        )
+    }
+
+    // the message of a protocol error
+    fn protocol_error<T: std::fmt::Debug>(res: Result<T>) -> String {
+        match res {
+            Err(RdmaKvError::Protocol(msg)) => msg,
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+
+    fn header(magic: u32, version: u16, len: u16) -> Vec<u8> {
+        let mut bytes = magic.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(&full()).unwrap();
+        let (version, peer): (u16, Capabilities) = decode(&bytes).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(peer.buf_size, 4096);
+        // trailing bytes are ignored, the stream delivers the same
+        let mut longer = bytes.clone();
+        longer.extend_from_slice(b"more");
+        assert!(decode::<Capabilities>(&longer).is_ok());
+        let (_, peer): (u16, Capabilities) = read(&mut &bytes[..]).unwrap();
+        assert_eq!(peer.opcodes, full().opcodes);
+    }
+
+    #[test]
+    fn bad_headers() {
+        let cases = [
+            (
+                header(0x4854_5450, PROTOCOL_VERSION, 0),
+                "not an rdma-kv peer",
+            ),
+            (header(MAGIC, MIN_PROTOCOL_VERSION - 1, 0), "at least"),
+            (
+                header(MAGIC, PROTOCOL_VERSION, MAX_PAYLOAD as u16 + 1),
+                "too long",
+            ),
+            (
+                header(MAGIC, PROTOCOL_VERSION, 0)[..HEADER_SIZE - 1].to_vec(),
+                "too short",
+            ),
+            (header(MAGIC, PROTOCOL_VERSION, 10), "cut short"),
+        ];
+        for (bytes, expected) in cases {
+            let msg = protocol_error(decode::<Capabilities>(&bytes));
+            assert!(msg.contains(expected), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn newer_peers_are_spoken_to_in_our_version() {
+        let mut bytes = encode(&full()).unwrap();
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let (version, peer): (u16, Capabilities) = decode(&bytes).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION + 1);
+        let negotiated = full().negotiate(&peer, version).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn mismatched_peers() {
+        let cases = [
+            (
+                caps(OP_RDMA_READ, 0, 4096, ibv_mtu::IBV_MTU_1024),
+                "are needed",
+            ),
+            (
+                caps(
+                    OP_WRITE_WITH_IMM,
+                    0,
+                    FRAME_HEADER_SIZE as u32,
+                    ibv_mtu::IBV_MTU_1024,
+                ),
+                "too small",
+            ),
+            (caps(OP_WRITE_WITH_IMM, 0, 4096, 0), "MTU 0 is invalid"),
+            (
+                caps(OP_WRITE_WITH_IMM, 0, 4096, ibv_mtu::IBV_MTU_4096 + 1),
+                "is invalid",
+            ),
+        ];
+        for (peer, expected) in cases {
+            let msg = protocol_error(full().negotiate(&peer, PROTOCOL_VERSION));
+            assert!(msg.contains(expected), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn intersection() {
+        let peer = caps(
+            OP_WRITE_WITH_IMM | OP_RDMA_READ,
+            FEATURE_INDEX | FEATURE_COUNTERS,
+            1024,
+            ibv_mtu::IBV_MTU_1024,
+        );
+        let negotiated = full().negotiate(&peer, PROTOCOL_VERSION).unwrap();
+        assert_eq!(negotiated.opcodes, OP_WRITE_WITH_IMM | OP_RDMA_READ);
+        assert_eq!(negotiated.features, FEATURE_INDEX | FEATURE_COUNTERS);
+        assert_eq!(negotiated.buf_size, 1024);
+        assert_eq!(negotiated.mtu, ibv_mtu::IBV_MTU_1024);
+        assert!(negotiated.has(FEATURE_INDEX));
+        // offered, but the atomics are missing
+        assert!(!negotiated.has(FEATURE_COUNTERS));
+
+        let negotiated = full().negotiate(&full(), PROTOCOL_VERSION).unwrap();
+        assert!(negotiated.has(FEATURE_COUNTERS));
+        let peer = caps(full().opcodes, 0, 4096, ibv_mtu::IBV_MTU_4096);
+        let negotiated = full().negotiate(&peer, PROTOCOL_VERSION).unwrap();
+        assert!(!negotiated.has(FEATURE_INDEX));
+        assert!(!negotiated.has(FEATURE_COUNTERS));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::error::Result;
+use crate::transport::Transport;
+use std::{
+    collections::HashSet,
+    sync::atomic::{fence, Ordering},
+};
+
+/// Size of one slot of the index.
+///
+/// ```text
+/// 0      8         16        18        20     21         24
+/// | version | checksum | key_len | val_len | flags | reserved | key | value |
+/// ```
+///
+/// All integers are little endian. The checksum covers the version and
+/// everything from `key_len` to the end of the value, a slot with an odd
+/// version is being written and a slot with version 0 was never written.
+pub const SLOT_SIZE: usize = 256;
+
+/// Number of slots a key may live in, starting at its home bucket. They are
+/// contiguous, so a client fetches them all with a single RDMA READ.
+pub const PROBE_LEN: usize = 8;
+
+const HEADER_SIZE: usize = 24;
+const SLOT_DATA_SIZE: usize = SLOT_SIZE - HEADER_SIZE;
+// set on a home bucket while one of its keys is not indexed
+const FLAG_OVERFLOW: u8 = 1;
+// the value of the slot is the number of the counter slot holding it
+const FLAG_COUNTER: u8 = 2;
+
+/// Size of the memory region holding an index of `buckets` buckets. The last
+/// buckets get `PROBE_LEN - 1` extra slots so that no window wraps around.
+pub fn region_size(buckets: usize) -> usize {
+    if buckets == 0 {
+        0
+    } else {
+        (buckets + PROBE_LEN - 1) * SLOT_SIZE
+    }
+}
+
+/// Number of buckets of an index living in a region of `size` bytes.
+pub fn buckets(size: usize) -> usize {
+    (size / SLOT_SIZE).saturating_sub(PROBE_LEN - 1)
+}
+
+/// Offset and length, in bytes, of the slots `key` may live in.
+pub fn window(key: &str, buckets: usize) -> (usize, usize) {
+    let home = (fnv1a(FNV_OFFSET, key.as_bytes()) % buckets as u64) as usize;
+    (home * SLOT_SIZE, PROBE_LEN * SLOT_SIZE)
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+// checksum of a slot whose header claims `data_len` bytes of key and value
+fn checksum(slot: &[u8], data_len: usize) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &slot[..8]);
+    fnv1a(hash, &slot[16..HEADER_SIZE + data_len])
+}
+
+/// What a client learns from the slots of a key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    Found(String),
+    /// The value lives in this counter slot.
+    Counter(usize),
+    Absent,
+    /// The key may exist but is not in the index, ask the server.
+    Unindexed,
+    /// A slot was being written while it was read, read again.
+    Torn,
+}
+
+/// Look `key` up in `window`, the bytes read from its window.
+pub fn lookup(window: &[u8], key: &str) -> Lookup {
+    let mut overflow = false;
+    for (n, slot) in window.chunks_exact(SLOT_SIZE).enumerate() {
+        let version = u64::from_le_bytes(slot[..8].try_into().unwrap());
+        if version == 0 {
+            continue;
+        }
+        let key_len = u16::from_le_bytes(slot[16..18].try_into().unwrap()) as usize;
+        let val_len = u16::from_le_bytes(slot[18..20].try_into().unwrap()) as usize;
+        if version % 2 == 1
+            || key_len + val_len > SLOT_DATA_SIZE
+            || u64::from_le_bytes(slot[8..16].try_into().unwrap())
+                != checksum(slot, key_len + val_len)
+        {
+            return Lookup::Torn;
+        }
+        if n == 0 && slot[20] & FLAG_OVERFLOW != 0 {
+            overflow = true;
+        }
+        let data = &slot[HEADER_SIZE..];
+        if key_len != 0 && &data[..key_len] == key.as_bytes() {
+            let value = match String::from_utf8(data[key_len..key_len + val_len].to_vec()) {
+                Ok(value) => value,
+                Err(_) => return Lookup::Torn,
+            };
+            if slot[20] & FLAG_COUNTER == 0 {
+                return Lookup::Found(value);
+            }
+            return match value.parse() {
+                Ok(counter) => Lookup::Counter(counter),
+                Err(_) => Lookup::Torn,
+            };
+        }
+    }
+    if overflow {
+        Lookup::Unindexed
+    } else {
+        Lookup::Absent
+    }
+}
+
+#[derive(Default, Clone)]
+struct Slot {
+    version: u64,
+    flags: u8,
+    // empty when the slot is free
+    key: String,
+    value: String,
+}
+
+impl Slot {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; SLOT_SIZE];
+        bytes[..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[16..18].copy_from_slice(&(self.key.len() as u16).to_le_bytes());
+        bytes[18..20].copy_from_slice(&(self.value.len() as u16).to_le_bytes());
+        bytes[20] = self.flags;
+        let data_len = self.key.len() + self.value.len();
+        bytes[HEADER_SIZE..HEADER_SIZE + self.key.len()].copy_from_slice(self.key.as_bytes());
+        bytes[HEADER_SIZE + self.key.len()..HEADER_SIZE + data_len]
+            .copy_from_slice(self.value.as_bytes());
+        let checksum = checksum(&bytes, data_len);
+        bytes[8..16].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+}
+
+/// The server side of the index, it mirrors the exposed region so that
+/// updates never have to read it back.
+///
+/// A key lives in one of the `PROBE_LEN` slots starting at its home bucket.
+/// Keys that do not fit, because the window is full or key and value are
+/// larger than a slot, are left out and their home bucket is flagged so that
+/// clients ask the server instead, as are keys that expire. The flag is
+/// cleared once none of the keys of the bucket is left out. A counter is
+/// indexed with the number of the counter slot holding its value.
+pub struct ReadIndex {
+    buckets: usize,
+    slots: Vec<Slot>,
+    // the keys left out, and how many of them each home bucket has
+    excluded: HashSet<String>,
+    excluded_per_home: Vec<u32>,
+}
+
+impl ReadIndex {
+    /// The index living in the local index region of `transport`, an empty
+    /// region gives an index that ignores every update.
+    pub fn new<T: Transport>(transport: &T) -> Self {
+        let buckets = buckets(transport.index_size());
+        ReadIndex {
+            buckets,
+            slots: vec![Slot::default(); region_size(buckets) / SLOT_SIZE],
+            excluded: HashSet::new(),
+            excluded_per_home: vec![0; buckets],
+        }
+    }
+
+    fn home(&self, key: &str) -> usize {
+        window(key, self.buckets).0 / SLOT_SIZE
+    }
+
+    pub fn set<T: Transport>(&mut self, transport: &mut T, key: &str, value: &str) -> Result<()> {
+        self.insert(transport, key, value, 0).map(|_| ())
+    }
+
+    /// Index `key` as a counter living in counter slot `counter`, returns
+    /// false when it could not be indexed.
+    pub fn set_counter<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        key: &str,
+        counter: usize,
+    ) -> Result<bool> {
+        self.insert(transport, key, &counter.to_string(), FLAG_COUNTER)
+    }
+
+    fn insert<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        key: &str,
+        value: &str,
+        flags: u8,
+    ) -> Result<bool> {
+        if self.buckets == 0 {
+            return Ok(false);
+        }
+        let home = self.home(key);
+        let window = home..home + PROBE_LEN;
+        let n = self.slots[window.clone()]
+            .iter()
+            .position(|slot| slot.key == key)
+            .or_else(|| {
+                self.slots[window]
+                    .iter()
+                    .position(|slot| slot.key.is_empty())
+            })
+            .map(|n| home + n);
+        match n {
+            Some(n) if !key.is_empty() && key.len() + value.len() <= SLOT_DATA_SIZE => {
+                self.slots[n].key = key.to_string();
+                self.slots[n].value = value.to_string();
+                self.slots[n].flags = self.slots[n].flags & FLAG_OVERFLOW | flags;
+                self.publish(transport, n)?;
+                self.include(transport, key)?;
+                Ok(true)
+            }
+            _ => {
+                // a stale value must not outlive the new one
+                self.exclude(transport, key)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Leave `key` out of the index, clients ask the server about it.
+    pub fn exclude<T: Transport>(&mut self, transport: &mut T, key: &str) -> Result<()> {
+        if self.buckets == 0 {
+            return Ok(());
+        }
+        // flagged before the slot goes, so that clients never find the key
+        // absent while it exists
+        let home = self.home(key);
+        if self.excluded.insert(key.to_string()) {
+            self.excluded_per_home[home] += 1;
+            if self.excluded_per_home[home] == 1 {
+                self.slots[home].flags |= FLAG_OVERFLOW;
+                self.publish(transport, home)?;
+            }
+        }
+        self.clear(transport, key)
+    }
+
+    /// Drop `key`, which no longer exists, from the index.
+    pub fn remove<T: Transport>(&mut self, transport: &mut T, key: &str) -> Result<()> {
+        self.clear(transport, key)?;
+        self.include(transport, key)
+    }
+
+    // undo the exclusion of key, if it was excluded, once its slot is right
+    fn include<T: Transport>(&mut self, transport: &mut T, key: &str) -> Result<()> {
+        if !self.excluded.remove(key) {
+            return Ok(());
+        }
+        let home = self.home(key);
+        self.excluded_per_home[home] -= 1;
+        if self.excluded_per_home[home] == 0 {
+            self.slots[home].flags &= !FLAG_OVERFLOW;
+            self.publish(transport, home)?;
+        }
+        Ok(())
+    }
+
+    // free the slot of key
+    fn clear<T: Transport>(&mut self, transport: &mut T, key: &str) -> Result<()> {
+        if self.buckets == 0 || key.is_empty() {
+            return Ok(());
+        }
+        let home = self.home(key);
+        if let Some(n) = self.slots[home..home + PROBE_LEN]
+            .iter()
+            .position(|slot| slot.key == key)
+        {
+            self.slots[home + n].key.clear();
+            self.slots[home + n].value.clear();
+            self.slots[home + n].flags &= FLAG_OVERFLOW;
+            self.publish(transport, home + n)?;
+        }
+        Ok(())
+    }
+
+    // write slot n seqlock style: readers that see the odd version, or a
+    // mix of the old and the new content, fail the checksum and retry
+    fn publish<T: Transport>(&mut self, transport: &mut T, n: usize) -> Result<()> {
+        let offset = n * SLOT_SIZE;
+        let slot = &mut self.slots[n];
+        slot.version += 1;
+        transport.write_index(&slot.version.to_le_bytes(), offset)?;
+        fence(Ordering::SeqCst);
+        slot.version += 1;
+        let bytes = slot.encode();
+        transport.write_index(&bytes[8..], offset + 8)?;
+        fence(Ordering::SeqCst);
+        transport.write_index(&bytes[..8], offset)
+    }
+}
@@ -0,0 +1,680 @@
+use crate::cli::RdmaOpt;
+use crate::error::{RdmaKvError, Result};
+use crate::events::{ConnectionStatus, TransportStatus};
+use crate::index;
+use crate::transport::{make_wr_id, wr_id_qp, Completion, CounterOp, Transport};
+use rdma_sys::{ibv_wc_opcode, ibv_wc_status, ibv_wr_opcode};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::debug;
+
+// what one side of the loopback exposes to its peer, it plays the role of
+// the registered memory and the receive queue of a QP
+struct Side {
+    bufs: Vec<Vec<u8>>,
+    // the region read by RDMA READs of the peer's index
+    index: Vec<u8>,
+    // wr_ids of the receives posted on each QP
+    recv_posted: Vec<VecDeque<u64>>,
+    // what arrived before a receive was posted, kept until one is
+    pending: Vec<VecDeque<Inbound>>,
+    completions: VecDeque<Completion>,
+}
+
+// what consumes a receive: the bytes of a send, or the immediate data of a
+// write whose bytes already landed in the buffer
+enum Inbound {
+    Send(Vec<u8>),
+    WriteImm(u32),
+}
+
+// threads wait on the condvar, tasks on the notify. The counters are
+// outside the lock, like RDMA atomics they do not need it
+struct Shared {
+    side: Mutex<Side>,
+    counters: Box<[AtomicU64]>,
+    cvar: Condvar,
+    notify: Notify,
+}
+
+impl Shared {
+    fn notify_completion(&self) {
+        self.cvar.notify_all();
+        self.notify.notify_one();
+    }
+}
+
+type SharedSide = Arc<Shared>;
+
+/// An in-process transport.
+///
+/// Two endpoints are created together by `Loopback::pair`, a write or send on
+/// one of them lands in the buffers of the other one. The semantics follow
+/// the RC queue pairs used by `RdmaContext`: writes and reads never involve
+/// the peer's CPU, sends and writes with immediate consume a receive posted
+/// by the peer and every work request produces a completion on the side that
+/// posted it.
+pub struct Loopback {
+    local: SharedSide,
+    remote: SharedSide,
+    // which connections are up, one per buffer
+    connected: Vec<bool>,
+    next_wr_seq: u32,
+}
+
+impl Loopback {
+    /// Both endpoints get `buf_num` buffers, and the index region and counter
+    /// slots a server would expose, sized after `config`.
+    pub fn pair(buf_num: usize, config: &RdmaOpt) -> (Loopback, Loopback) {
+        let new_side = || -> SharedSide {
+            Arc::new(Shared {
+                counters: (0..config.counter_slots)
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+                side: Mutex::new(Side {
+                    bufs: vec![vec![0; config.buf_size]; buf_num],
+                    index: vec![0; index::region_size(config.index_buckets)],
+                    recv_posted: vec![VecDeque::new(); buf_num],
+                    pending: (0..buf_num).map(|_| VecDeque::new()).collect(),
+                    completions: VecDeque::new(),
+                }),
+                cvar: Condvar::new(),
+                notify: Notify::new(),
+            })
+        };
+        let (a, b) = (new_side(), new_side());
+        (
+            Loopback {
+                local: a.clone(),
+                remote: b.clone(),
+                connected: vec![false; buf_num],
+                next_wr_seq: 0,
+            },
+            Loopback {
+                local: b,
+                remote: a,
+                connected: vec![false; buf_num],
+                next_wr_seq: 0,
+            },
+        )
+    }
+
+    fn check_connected(&self, i: usize) -> Result<()> {
+        if self.connected.get(i) == Some(&true) {
+            Ok(())
+        } else {
+            Err(RdmaKvError::not_connected(i))
+        }
+    }
+
+    fn next_wr_id(&mut self, i: usize) -> u64 {
+        let wr_id = make_wr_id(i, self.next_wr_seq);
+        self.next_wr_seq = self.next_wr_seq.wrapping_add(1);
+        wr_id
+    }
+
+    fn complete(shared: &SharedSide, wc: Completion) {
+        shared.side.lock().unwrap().completions.push_back(wc);
+        shared.notify_completion();
+    }
+
+    // complete the receive `wr_id` with what arrived
+    fn receive(shared: &SharedSide, side: &mut Side, wr_id: u64, inbound: Inbound) {
+        let i = wr_id_qp(wr_id);
+        let wc = match inbound {
+            Inbound::Send(bytes) => {
+                side.bufs[i][..bytes.len()].copy_from_slice(&bytes);
+                Completion {
+                    wr_id,
+                    qp: i,
+                    status: ibv_wc_status::IBV_WC_SUCCESS,
+                    opcode: ibv_wc_opcode::IBV_WC_RECV,
+                    byte_len: bytes.len() as u32,
+                    imm_data: None,
+                }
+            }
+            Inbound::WriteImm(imm_data) => Completion {
+                wr_id,
+                qp: i,
+                status: ibv_wc_status::IBV_WC_SUCCESS,
+                opcode: ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM,
+                byte_len: imm_data,
+                imm_data: Some(imm_data),
+            },
+        };
+        side.completions.push_back(wc);
+        shared.notify_completion();
+    }
+
+    // never hold the locks of both sides at once, the peer may be posting too
+    fn deliver(shared: &SharedSide, inbound: Inbound, i: usize) {
+        let mut side = shared.side.lock().unwrap();
+        match side.recv_posted[i].pop_front() {
+            Some(wr_id) => Self::receive(shared, &mut side, wr_id, inbound),
+            None => side.pending[i].push_back(inbound),
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn buf_size(&self, i: usize) -> usize {
+        self.local.side.lock().unwrap().bufs[i].len()
+    }
+
+    fn connect(&mut self, _config: &RdmaOpt) -> Result<()> {
+        self.connected[0] = true;
+        debug!("Loopback endpoint connected");
+        Ok(())
+    }
+
+    // nothing can fail on the loopback, this only undoes a disconnect
+    fn reconnect(&mut self, config: &RdmaOpt) -> Result<()> {
+        self.disconnect(0);
+        self.connect(config)
+    }
+
+    // nothing blocks on the loopback
+    fn reconnect_async(&mut self, config: &RdmaOpt) -> impl Future<Output = Result<()>> + Send {
+        std::future::ready(self.reconnect(config))
+    }
+
+    // the peer is there from the start, every buffer is a connection
+    fn listen(&mut self, _config: &RdmaOpt) -> Result<()> {
+        for i in 0..self.connected.len() {
+            self.connected[i] = true;
+            self.post_receive(i)?;
+        }
+        debug!("Loopback endpoint listening");
+        Ok(())
+    }
+
+    // outstanding receives are dropped with the connection
+    fn disconnect(&mut self, i: usize) {
+        if let Some(connected) = self.connected.get_mut(i) {
+            *connected = false;
+            let mut side = self.local.side.lock().unwrap();
+            side.recv_posted[i].clear();
+            side.pending[i].clear();
+            debug!("Loopback connection {} dropped", i);
+        }
+    }
+
+    fn post_send(&mut self, opcode: ibv_wr_opcode::Type, i: usize, len: usize) -> Result<u64> {
+        self.check_connected(i)?;
+        if len > self.buf_size(i) {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "{} bytes exceed the {} byte buffer",
+                len,
+                self.buf_size(i)
+            )));
+        }
+        let wc_opcode = match opcode {
+            ibv_wr_opcode::IBV_WR_RDMA_WRITE => {
+                let bytes = self.read_buf(i);
+                self.remote.side.lock().unwrap().bufs[i][..len].copy_from_slice(&bytes[..len]);
+                debug!("RDMA write request was posted");
+                ibv_wc_opcode::IBV_WC_RDMA_WRITE
+            }
+            ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM => {
+                let bytes = self.read_buf(i);
+                self.remote.side.lock().unwrap().bufs[i][..len].copy_from_slice(&bytes[..len]);
+                Self::deliver(&self.remote, Inbound::WriteImm(len as u32), i);
+                debug!("RDMA write with immediate request was posted");
+                ibv_wc_opcode::IBV_WC_RDMA_WRITE
+            }
+            ibv_wr_opcode::IBV_WR_SEND => {
+                let mut bytes = self.read_buf(i);
+                bytes.truncate(len);
+                Self::deliver(&self.remote, Inbound::Send(bytes), i);
+                debug!("Send request was posted");
+                ibv_wc_opcode::IBV_WC_SEND
+            }
+            ibv_wr_opcode::IBV_WR_RDMA_READ => {
+                let bytes = self.remote.side.lock().unwrap().bufs[i][..len].to_vec();
+                self.write_buf(&bytes, i);
+                debug!("RDMA read request was posted");
+                ibv_wc_opcode::IBV_WC_RDMA_READ
+            }
+            _ => {
+                return Err(RdmaKvError::InvalidInput(format!(
+                    "opcode {} is not supported by the loopback",
+                    opcode
+                )))
+            }
+        };
+        let wr_id = self.next_wr_id(i);
+        Self::complete(
+            &self.local,
+            Completion {
+                wr_id,
+                qp: i,
+                status: ibv_wc_status::IBV_WC_SUCCESS,
+                opcode: wc_opcode,
+                byte_len: len as u32,
+                imm_data: None,
+            },
+        );
+        Ok(wr_id)
+    }
+
+    fn post_read_index(&mut self, i: usize, offset: usize, len: usize) -> Result<u64> {
+        self.check_connected(i)?;
+        if len > self.buf_size(i) || offset + len > self.remote_index_size(i) {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "index read of {} bytes at {} is out of bounds",
+                len, offset
+            )));
+        }
+        let bytes = self.remote.side.lock().unwrap().index[offset..offset + len].to_vec();
+        self.write_buf(&bytes, i);
+        debug!("RDMA read request was posted");
+        let wr_id = self.next_wr_id(i);
+        Self::complete(
+            &self.local,
+            Completion {
+                wr_id,
+                qp: i,
+                status: ibv_wc_status::IBV_WC_SUCCESS,
+                opcode: ibv_wc_opcode::IBV_WC_RDMA_READ,
+                byte_len: len as u32,
+                imm_data: None,
+            },
+        );
+        Ok(wr_id)
+    }
+
+    fn post_counter_op(&mut self, i: usize, slot: usize, op: CounterOp) -> Result<u64> {
+        self.check_connected(i)?;
+        let counter = self.remote.counters.get(slot).ok_or_else(|| {
+            RdmaKvError::InvalidInput(format!("counter slot {} is out of bounds", slot))
+        })?;
+        let (old, wc_opcode) = match op {
+            CounterOp::Read => (
+                counter.load(Ordering::SeqCst),
+                ibv_wc_opcode::IBV_WC_RDMA_READ,
+            ),
+            CounterOp::FetchAdd(add) => (
+                counter.fetch_add(add, Ordering::SeqCst),
+                ibv_wc_opcode::IBV_WC_FETCH_ADD,
+            ),
+            CounterOp::CompareSwap { compare, swap } => (
+                match counter.compare_exchange(compare, swap, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(old) | Err(old) => old,
+                },
+                ibv_wc_opcode::IBV_WC_COMP_SWAP,
+            ),
+        };
+        self.write_buf(&old.to_ne_bytes(), i);
+        debug!("Counter request {:?} was posted", op);
+        let wr_id = self.next_wr_id(i);
+        Self::complete(
+            &self.local,
+            Completion {
+                wr_id,
+                qp: i,
+                status: ibv_wc_status::IBV_WC_SUCCESS,
+                opcode: wc_opcode,
+                byte_len: 8,
+                imm_data: None,
+            },
+        );
+        Ok(wr_id)
+    }
+
+    fn post_receive(&mut self, i: usize) -> Result<u64> {
+        self.check_connected(i)?;
+        let wr_id = self.next_wr_id(i);
+        let mut side = self.local.side.lock().unwrap();
+        match side.pending[i].pop_front() {
+            Some(inbound) => Self::receive(&self.local, &mut side, wr_id, inbound),
+            None => side.recv_posted[i].push_back(wr_id),
+        }
+        debug!("Receive request was posted");
+        Ok(wr_id)
+    }
+
+    fn poll_completion(&self) -> Result<Completion> {
+        let mut side = self
+            .local
+            .cvar
+            .wait_while(self.local.side.lock().unwrap(), |side| {
+                side.completions.is_empty()
+            })
+            .unwrap();
+        Ok(side.completions.pop_front().unwrap())
+    }
+
+    fn poll_completion_timeout(&self, timeout: Duration) -> Result<Option<Completion>> {
+        let (mut side, _) = self
+            .local
+            .cvar
+            .wait_timeout_while(self.local.side.lock().unwrap(), timeout, |side| {
+                side.completions.is_empty()
+            })
+            .unwrap();
+        Ok(side.completions.pop_front())
+    }
+
+    async fn poll_completion_async(&self) -> Result<Completion> {
+        loop {
+            if let Some(wc) = self.local.side.lock().unwrap().completions.pop_front() {
+                return Ok(wc);
+            }
+            // a completion between the check and here leaves a permit behind
+            self.local.notify.notified().await;
+        }
+    }
+
+    fn read_buf(&self, i: usize) -> Vec<u8> {
+        self.local.side.lock().unwrap().bufs[i].clone()
+    }
+
+    fn write_buf(&mut self, bytes: &[u8], i: usize) {
+        self.local.side.lock().unwrap().bufs[i][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn index_size(&self) -> usize {
+        self.local.side.lock().unwrap().index.len()
+    }
+
+    fn write_index(&mut self, bytes: &[u8], offset: usize) -> Result<()> {
+        let mut side = self.local.side.lock().unwrap();
+        let region = side
+            .index
+            .get_mut(offset..offset + bytes.len())
+            .ok_or_else(|| {
+                RdmaKvError::InvalidInput(format!(
+                    "index write of {} bytes at {} is out of bounds",
+                    bytes.len(),
+                    offset
+                ))
+            })?;
+        region.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn remote_index_size(&self, _i: usize) -> usize {
+        self.remote.side.lock().unwrap().index.len()
+    }
+
+    fn counter_slots(&self) -> usize {
+        self.local.counters.len()
+    }
+
+    fn counter(&self, slot: usize) -> Result<&AtomicU64> {
+        self.local.counters.get(slot).ok_or_else(|| {
+            RdmaKvError::InvalidInput(format!("counter slot {} is out of bounds", slot))
+        })
+    }
+
+    fn remote_counter_slots(&self, _i: usize) -> usize {
+        self.remote.counters.len()
+    }
+
+    // there is no port that could go down and no async events
+    fn status(&self) -> TransportStatus {
+        TransportStatus {
+            port_active: true,
+            connections: self
+                .connected
+                .iter()
+                .enumerate()
+                .filter(|(_, &connected)| connected)
+                .map(|(id, _)| ConnectionStatus {
+                    id,
+                    qp_num: None,
+                    healthy: true,
+                    reason: None,
+                })
+                .collect(),
+            events: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{EngineKind, FsyncPolicy, KeyValueOpt, Page, KEY_NOT_FOUND};
+    use crate::client::KvClient;
+    use crate::engine;
+    use crate::expiry::Expiry;
+    use crate::index::{Lookup, ReadIndex};
+    use crate::server::KvServer;
+    use crate::snapshot::{self, Snapshots};
+    use crate::temp_dir::TempDir;
+    use crate::wal::Wal;
+
+    // a client of a server that runs on its own thread, both in-process
+    fn serve(config: &RdmaOpt) -> KvClient<Loopback> {
+        serve_with(config, |server_end| {
+            KvServer::new(
+                server_end,
+                engine::new(EngineKind::Btree),
+                Expiry::default(),
+            )
+        })
+    }
+
+    // same, with the server new_server builds on its end
+    fn serve_with(
+        config: &RdmaOpt,
+        new_server: impl FnOnce(Loopback) -> Result<KvServer<Loopback>>,
+    ) -> KvClient<Loopback> {
+        let (server_end, client_end) = Loopback::pair(1, config);
+        let mut server = new_server(server_end).unwrap();
+        server.listen(config).unwrap();
+        std::thread::spawn(move || server.process_kv_opt());
+        let mut client = KvClient::new(client_end);
+        client.connect(config).unwrap();
+        client
+    }
+
+    fn run(client: &mut KvClient<Loopback>, kv_opt: KeyValueOpt) -> Option<String> {
+        client.execute(&kv_opt).unwrap()
+    }
+
+    fn get(client: &mut KvClient<Loopback>, key: &str) -> String {
+        run(client, KeyValueOpt::Get { key: key.into() }).unwrap()
+    }
+
+    #[test]
+    fn set_get_delete() {
+        let mut client = serve(&RdmaOpt::default());
+        let set = |key: &str, value: &str| KeyValueOpt::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl: None,
+        };
+        assert_eq!(get(&mut client, "a"), KEY_NOT_FOUND);
+        assert_eq!(run(&mut client, set("a", "1")), None);
+        assert_eq!(get(&mut client, "a"), "1");
+        run(&mut client, set("a", "2"));
+        assert_eq!(get(&mut client, "a"), "2");
+        assert_eq!(
+            run(&mut client, KeyValueOpt::Delete { key: "a".into() }),
+            None
+        );
+        assert_eq!(get(&mut client, "a"), KEY_NOT_FOUND);
+    }
+
+    #[test]
+    fn counters() {
+        // with counter slots the increments are RDMA atomics, without them
+        // the server adds
+        for counter_slots in [0, 4] {
+            let mut client = serve(&RdmaOpt {
+                counter_slots,
+                ..Default::default()
+            });
+            for n in 1..=5 {
+                let incr = KeyValueOpt::Incr { key: "n".into() };
+                assert_eq!(run(&mut client, incr), Some(n.to_string()));
+            }
+            let decr = KeyValueOpt::Decr { key: "n".into() };
+            assert_eq!(run(&mut client, decr), Some("4".to_string()));
+            assert_eq!(get(&mut client, "n"), "4");
+            run(&mut client, KeyValueOpt::Delete { key: "n".into() });
+            assert_eq!(get(&mut client, "n"), KEY_NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn paged_scan() {
+        // small buffers, so that a page takes several messages
+        let mut client = serve(&RdmaOpt {
+            buf_size: 256,
+            ..Default::default()
+        });
+        for i in 0..50 {
+            run(
+                &mut client,
+                KeyValueOpt::Set {
+                    key: format!("k{:02}", i),
+                    value: "v".repeat(16),
+                    ttl: None,
+                },
+            );
+        }
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let scan = KeyValueOpt::Scan {
+                start: None,
+                end: None,
+                limit: 20,
+                cursor,
+            };
+            let page: Page = serde_json::from_str(&run(&mut client, scan).unwrap()).unwrap();
+            assert!(page.entries.len() <= 20);
+            keys.extend(page.entries.into_iter().map(|(key, _)| key));
+            if page.cursor.is_none() {
+                break;
+            }
+            cursor = page.cursor;
+        }
+        let expected: Vec<_> = (0..50).map(|i| format!("k{:02}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn expired_keys_are_gone() {
+        let mut client = serve(&RdmaOpt::default());
+        let set = |key: &str, ttl: Option<u64>| KeyValueOpt::Set {
+            key: key.into(),
+            value: format!("v{}", key),
+            ttl,
+        };
+        let ttl = |client: &mut KvClient<Loopback>, key: &str| {
+            run(client, KeyValueOpt::Ttl { key: key.into() }).unwrap()
+        };
+        run(&mut client, set("a", Some(1)));
+        run(&mut client, set("b", Some(100)));
+        run(&mut client, set("c", None));
+        run(&mut client, set("d", None));
+        assert_eq!(ttl(&mut client, "a"), "1");
+        assert_eq!(ttl(&mut client, "b"), "100");
+        assert_eq!(ttl(&mut client, "c"), "-1");
+        assert_eq!(ttl(&mut client, "x"), "-2");
+        assert_eq!(get(&mut client, "a"), "va");
+        // a value from the index now would outlive the deadline
+        assert_eq!(get(&mut client, "c"), "vc");
+        let expire = KeyValueOpt::Expire {
+            key: "c".into(),
+            seconds: 1,
+        };
+        assert_eq!(run(&mut client, expire), Some("1".to_string()));
+        let persist = |key: &str| KeyValueOpt::Persist { key: key.into() };
+        assert_eq!(run(&mut client, persist("b")), Some("1".to_string()));
+        assert_eq!(run(&mut client, persist("d")), Some("0".to_string()));
+        assert_eq!(ttl(&mut client, "b"), "-1");
+
+        std::thread::sleep(Duration::from_millis(1100));
+        for key in ["a", "c"] {
+            assert_eq!(get(&mut client, key), KEY_NOT_FOUND, "{}", key);
+            assert_eq!(ttl(&mut client, key), "-2", "{}", key);
+        }
+        let scan = KeyValueOpt::Scan {
+            start: None,
+            end: None,
+            limit: 10,
+            cursor: None,
+        };
+        let page: Page = serde_json::from_str(&run(&mut client, scan).unwrap()).unwrap();
+        let keys: Vec<_> = page.entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["b", "d"]);
+    }
+
+    #[test]
+    fn index_flag_follows_excluded_keys() {
+        // one bucket, every key has the same home
+        let config = RdmaOpt {
+            index_buckets: 1,
+            ..Default::default()
+        };
+        let (mut server_end, _) = Loopback::pair(1, &config);
+        let mut index = ReadIndex::new(&server_end);
+        let lookup = |server_end: &Loopback, key: &str| {
+            let (offset, len) = index::window(key, 1);
+            let side = server_end.local.side.lock().unwrap();
+            index::lookup(&side.index[offset..offset + len], key)
+        };
+        let big = "v".repeat(index::SLOT_SIZE);
+
+        index.set(&mut server_end, "a", "1").unwrap();
+        assert_eq!(lookup(&server_end, "x"), Lookup::Absent);
+        index.exclude(&mut server_end, "b").unwrap();
+        index.set(&mut server_end, "c", &big).unwrap();
+        assert_eq!(lookup(&server_end, "x"), Lookup::Unindexed);
+        // one key is still left out
+        index.set(&mut server_end, "b", "2").unwrap();
+        assert_eq!(lookup(&server_end, "b"), Lookup::Found("2".into()));
+        assert_eq!(lookup(&server_end, "x"), Lookup::Unindexed);
+        index.remove(&mut server_end, "c").unwrap();
+        assert_eq!(lookup(&server_end, "x"), Lookup::Absent);
+        assert_eq!(lookup(&server_end, "a"), Lookup::Found("1".into()));
+    }
+
+    #[test]
+    fn idle_server_snapshots() {
+        let dir = TempDir::new("idle-snapshot");
+        let (wal, snapshot) = (dir.join("wal"), dir.join("snapshot"));
+        let config = RdmaOpt::default();
+        let mut client = serve_with(&config, |server_end| {
+            let mut engine = engine::new(EngineKind::Btree);
+            let mut expiry = Expiry::default();
+            let wal = Wal::open(&wal, FsyncPolicy::Always, engine.as_mut(), &mut expiry)?;
+            let snapshots = Snapshots::new(snapshot.clone(), Some(Duration::from_millis(200)));
+            Ok(KvServer::new(server_end, engine, expiry)?
+                .with_wal(wal)
+                .with_snapshots(snapshots))
+        });
+        let set = KeyValueOpt::Set {
+            key: "a".into(),
+            value: "1".into(),
+            ttl: None,
+        };
+        run(&mut client, set);
+
+        // no request comes after the set, the timer alone saves and compacts
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !Wal::compacted(&wal).unwrap() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no snapshot was saved"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let mut engine = engine::new(EngineKind::Btree);
+        snapshot::restore(&snapshot, engine.as_mut(), &mut Expiry::default()).unwrap();
+        assert_eq!(engine.get("a").as_deref(), Some("1"));
+    }
+}
@@ -1,12 +1,44 @@
 use clap::Parser;
+mod auth;
 mod cli;
+mod client;
+mod cm;
 mod context;
+mod devices;
+mod engine;
+mod error;
+mod events;
+mod expiry;
+mod frame;
 mod gid;
-use axum::{extract::State, routing::post, Json, Router};
-use cli::{KeyValueOpt, RdmaOpt};
-use context::RdmaContext;
-use rdma_sys::ibv_wr_opcode;
-use std::{sync::{Arc, Mutex}, time::Instant};
+mod handshake;
+mod index;
+mod loopback;
+mod server;
+mod snapshot;
+#[cfg(test)]
+mod temp_dir;
+mod transport;
+mod verbs;
+mod wal;
+use axum::{
+    extract::State,
+    routing::{get, post},
+    Json, Router,
+};
+use cli::{Command, KeyValueOpt, RdmaOpt};
+use client::KvClient;
+use context::{RdmaContext, StatusHandle};
+use error::{RdmaKvError, Result};
+use events::TransportStatus;
+use expiry::Expiry;
+use loopback::Loopback;
+use server::KvServer;
+use snapshot::Snapshots;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use transport::Transport;
+use wal::Wal;
 
 #[tokio::main]
 async fn main() {
@@ -15,49 +47,142 @@ async fn main() {
         .init();
     let config = RdmaOpt::parse();
     println!("Current Config: {:?}", config);
-    let mut rdma_context = RdmaContext::create(&config).unwrap();
 
-    rdma_context.connect_qp(&config).unwrap();
+    if let Err(e) = run(config).await {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(config: RdmaOpt) -> Result<()> {
+    if let Some(Command::Devices) = config.command {
+        return devices::list();
+    }
+    if config.loopback {
+        let (engine, expiry, wal) = open_store(&config)?;
+        let (server_end, client_end) = Loopback::pair(1, &config);
+        let mut server = new_server(server_end, &config, engine, expiry, wal)?;
+        server.listen(&config)?;
+        std::thread::spawn(move || {
+            if let Err(e) = server.process_kv_opt() {
+                tracing::error!("Server stopped: {}", e);
+            }
+        });
+        let mut kv_client = KvClient::new(client_end);
+        kv_client.connect(&config)?;
+        run_client(&mut kv_client);
+        return serve_http(kv_client).await;
+    }
+
+    let rdma_context = RdmaContext::create(&config)?;
 
     if config.server.is_some() {
         // client
-        loop {
-            if let Ok(kv_opt) = cli::client_opt() {
-                if let KeyValueOpt::Get { key: _ } = kv_opt {
-                    rdma_context.post_receive(0).unwrap();
-                }
-
-                let mut send_str = serde_json::to_vec(&kv_opt).unwrap();
-                rdma_context.set_bytes_to_buf(&mut send_str, 0);
-                println!("time: {:?}", Instant::now());
-                rdma_context
-                    .post_send(ibv_wr_opcode::IBV_WR_RDMA_WRITE, 0)
-                    .unwrap();
-                rdma_context.poll_completion().unwrap();
-
-                if let KeyValueOpt::Get { key: _ } = kv_opt {
-                    rdma_context.poll_completion().unwrap();
-                    let value = rdma_context.read_the_buf(0);
-                    tracing::info!("get value: {}", value);
-                }
+        let mut kv_client = KvClient::new(rdma_context);
+        kv_client.connect(&config)?;
+        run_client(&mut kv_client);
+        serve_http(kv_client).await
+    } else {
+        // the log is replayed and indexed before any client can connect
+        let (engine, expiry, wal) = open_store(&config)?;
+        let status = rdma_context.status_handle();
+        let mut server = new_server(rdma_context, &config, engine, expiry, wal)?;
+        server.listen(&config)?;
+        if let Some(port) = config.status_port {
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+            tokio::spawn(serve_status(listener, status));
+        }
+        tokio::task::spawn_blocking(move || server.process_kv_opt())
+            .await
+            .unwrap_or_else(|e| Err(RdmaKvError::Store(format!("the server panicked: {}", e))))
+    }
+}
+
+// the engine of the server and the deadlines of its keys, with what its
+// snapshot and its WAL hold
+fn open_store(config: &RdmaOpt) -> Result<(Box<dyn engine::KvEngine>, Expiry, Option<Wal>)> {
+    let mut engine = engine::new(config.engine);
+    let mut expiry = Expiry::default();
+    let mut restore = config.restore.as_ref();
+    if let Some(path) = &config.wal {
+        // the records a compacted WAL dropped are only in the snapshot
+        if restore.is_none() && Wal::compacted(path)? {
+            restore = Some(config.snapshot.as_ref().ok_or_else(|| {
+                RdmaKvError::InvalidInput(format!(
+                    "{} was cut back after a snapshot, pass that snapshot with --restore",
+                    path.display()
+                ))
+            })?);
+        }
+    }
+    if let Some(path) = restore {
+        let keys = snapshot::restore(path, engine.as_mut(), &mut expiry)?;
+        tracing::info!("Restored {} keys from {}", keys, path.display());
+    }
+    let wal = match &config.wal {
+        Some(path) => Some(Wal::open(path, config.fsync, engine.as_mut(), &mut expiry)?),
+        None => None,
+    };
+    Ok((engine, expiry, wal))
+}
+
+fn new_server<T: Transport>(
+    transport: T,
+    config: &RdmaOpt,
+    engine: Box<dyn engine::KvEngine>,
+    expiry: Expiry,
+    wal: Option<Wal>,
+) -> Result<KvServer<T>> {
+    let mut server = KvServer::new(transport, engine, expiry)?;
+    if let Some(wal) = wal {
+        server = server.with_wal(wal);
+    }
+    if let Some(path) = &config.snapshot {
+        let interval =
+            (config.snapshot_interval > 0).then(|| Duration::from_secs(config.snapshot_interval));
+        server = server.with_snapshots(Snapshots::new(path.clone(), interval));
+    }
+    Ok(server)
+}
+
+fn run_client<T: Transport>(kv_client: &mut KvClient<T>) {
+    loop {
+        if let Ok(kv_opt) = cli::client_opt() {
+            match kv_client.execute(&kv_opt) {
+                Ok(Some(value)) => tracing::info!("get value: {}", value),
+                Ok(None) => {}
+                Err(e) => tracing::error!("{}", e),
             }
         }
-    } else {
-        rdma_context.process_kv_opt();
     }
+}
 
+async fn serve_http<T: Transport + Send + Sync + 'static>(kv_client: KvClient<T>) -> Result<()> {
     let app = Router::new()
-        .route("/login", post(login))
-        .route("/opt", post(kv_opt))
-        .with_state(Arc::new(Mutex::new(rdma_context)));
+        .route("/login", post(login::<T>))
+        .route("/opt", post(kv_opt::<T>))
+        .route("/status", get(status::<T>))
+        .with_state(Arc::new(Mutex::new(kv_client)));
 
     // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
 
+// the status API of a server, the health of its client connections
+async fn serve_status(listener: tokio::net::TcpListener, status: StatusHandle) {
+    let app = Router::new()
+        .route("/status", get(server_status))
+        .with_state(status);
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("Serving the status failed: {}", e);
+    }
 }
 
+async fn server_status(State(status): State<StatusHandle>) -> Json<TransportStatus> {
+    Json(status.status())
+}
 
 #[derive(serde::Deserialize)]
 struct LoginRequest {
@@ -70,14 +195,17 @@ struct LoginResponse {
     message: String,
 }
 
-async fn login(
-    State(rdma_context): State<Arc<Mutex<RdmaContext>>>,
+async fn login<T: Transport + Send + Sync + 'static>(
+    State(kv_client): State<Arc<Mutex<KvClient<T>>>>,
     Json(payload): Json<LoginRequest>,
 ) -> Json<LoginResponse> {
-    let mut config = RdmaOpt::default();
-    config.server = Some(payload.server_ip);
-    let mut ctx = rdma_context.lock().unwrap();
-    match ctx.connect_qp(&config) {
+    let mut kv_client = kv_client.lock().await;
+    // the device options and the secret stay those the client started with
+    let config = RdmaOpt {
+        server: Some(payload.server_ip),
+        ..kv_client.config().cloned().unwrap_or_default()
+    };
+    match kv_client.connect(&config) {
         Ok(_) => Json(LoginResponse {
             success: true,
             message: "Successfully connected to RDMA server".to_string(),
@@ -89,7 +217,6 @@ async fn login(
     }
 }
 
-
 #[derive(serde::Deserialize)]
 struct KvOptRequest {
     operation: KeyValueOpt,
@@ -101,48 +228,116 @@ struct KvOptResponse {
     result: String,
 }
 
-async fn kv_opt(
-    State(rdma_context): State<Arc<Mutex<RdmaContext>>>,
+async fn kv_opt<T: Transport + Send + Sync + 'static>(
+    State(kv_client): State<Arc<Mutex<KvClient<T>>>>,
     Json(payload): Json<KvOptRequest>,
 ) -> Json<KvOptResponse> {
+    let mut kv_client = kv_client.lock().await;
 
-    let mut ctx = rdma_context.lock().unwrap();
-    
-    // 将操作序列化并发送到RDMA缓冲区
-    let mut send_str = serde_json::to_vec(&payload.operation).unwrap();
-    ctx.set_bytes_to_buf(&mut send_str, 0);
-    
-    // 发送RDMA写请求
-    match ctx.post_send(ibv_wr_opcode::IBV_WR_RDMA_WRITE, 0) {
-        Ok(_) => {
-            // 等待完成
-            match ctx.poll_completion() {
-                Ok(_) => {
-                    // 如果是Get操作，需要接收响应
-                    if let KeyValueOpt::Get { key: _ } = payload.operation {
-                        ctx.post_receive(0).unwrap();
-                        ctx.poll_completion().unwrap();
-                        let value = ctx.read_the_buf(0);
-                        Json(KvOptResponse {
-                            success: true,
-                            result: value,
-                        })
-                    } else {
-                        Json(KvOptResponse {
-                            success: true,
-                            result: "Operation completed successfully".to_string(),
-                        })
-                    }
-                }
-                Err(e) => Json(KvOptResponse {
-                    success: false,
-                    result: format!("Operation failed: {}", e),
-                }),
-            }
-        }
+    // 将操作序列化并通过RDMA发送, Get操作会等待服务端的响应
+    match kv_client.execute_async(&payload.operation).await {
+        Ok(Some(value)) => Json(KvOptResponse {
+            success: true,
+            result: value,
+        }),
+        Ok(None) => Json(KvOptResponse {
+            success: true,
+            result: "Operation completed successfully".to_string(),
+        }),
         Err(e) => Json(KvOptResponse {
             success: false,
-            result: format!("Failed to send operation: {}", e),
+            result: format!("Operation failed: {}", e),
         }),
     }
 }
+
+async fn status<T: Transport + Send + Sync + 'static>(
+    State(kv_client): State<Arc<Mutex<KvClient<T>>>>,
+) -> Json<TransportStatus> {
+    Json(kv_client.lock().await.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::wal::Record;
+
+    // a WAL at dir/wal that dropped the set of a, which dir/snapshot holds
+    fn compacted(dir: &TempDir) {
+        let mut engine = engine::new(cli::EngineKind::Btree);
+        let mut wal = Wal::open(
+            &dir.join("wal"),
+            cli::FsyncPolicy::Always,
+            engine.as_mut(),
+            &mut Expiry::default(),
+        )
+        .unwrap();
+        wal.append(&Record::Set {
+            key: "a",
+            value: "1",
+        })
+        .unwrap();
+        let position = wal.position();
+        let entries = [("a".to_string(), "1".to_string(), None)];
+        snapshot::write(&dir.join("snapshot"), &entries).unwrap();
+        wal.append(&Record::Set {
+            key: "b",
+            value: "2",
+        })
+        .unwrap();
+        wal.drop_before(position).unwrap();
+    }
+
+    fn keys(engine: &dyn engine::KvEngine) -> Vec<String> {
+        let entries = engine.scan(std::ops::Bound::Unbounded, usize::MAX);
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn compacted_wal_needs_its_snapshot() {
+        let dir = TempDir::new("open-store");
+        compacted(&dir);
+        let config = RdmaOpt {
+            wal: Some(dir.join("wal")),
+            ..Default::default()
+        };
+        match open_store(&config) {
+            Err(RdmaKvError::InvalidInput(msg)) => assert!(msg.contains("--restore"), "{}", msg),
+            other => panic!("a compacted WAL was opened alone: {:?}", other.is_ok()),
+        }
+
+        // --snapshot stands in for --restore
+        let config = RdmaOpt {
+            snapshot: Some(dir.join("snapshot")),
+            ..config
+        };
+        let (engine, _, _) = open_store(&config).unwrap();
+        assert_eq!(keys(engine.as_ref()), ["a", "b"]);
+        let config = RdmaOpt {
+            snapshot: None,
+            restore: Some(dir.join("snapshot")),
+            ..config
+        };
+        let (engine, _, _) = open_store(&config).unwrap();
+        assert_eq!(keys(engine.as_ref()), ["a", "b"]);
+    }
+
+    #[test]
+    fn whole_wal_needs_no_snapshot() {
+        let dir = TempDir::new("open-store-whole");
+        let config = RdmaOpt {
+            wal: Some(dir.join("wal")),
+            ..Default::default()
+        };
+        let (_, _, wal) = open_store(&config).unwrap();
+        wal.unwrap()
+            .append(&Record::Set {
+                key: "a",
+                value: "1",
+            })
+            .unwrap();
+        let (engine, _, _) = open_store(&config).unwrap();
+        assert_eq!(keys(engine.as_ref()), ["a"]);
+    }
+}
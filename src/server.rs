@@ -0,0 +1,662 @@
+use crate::cli::{KeyValueOpt, KvReply, Page, PageChunk, PageReply, RdmaOpt, KEY_NOT_FOUND};
+use crate::engine::{self, KvEngine};
+use crate::error::{RdmaKvError, Result};
+use crate::expiry::{self, Expiry};
+use crate::frame::FRAME_HEADER_SIZE;
+use crate::index::ReadIndex;
+use crate::snapshot::Snapshots;
+use crate::transport::{Completion, Transport};
+use crate::wal::{Record, Wal};
+use rdma_sys::{ibv_wc_opcode, ibv_wr_opcode};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Bound,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+// most entries a page holds, whatever the client asks for
+const MAX_PAGE_LIMIT: usize = 10_000;
+
+// how often expired keys are swept, and how many at most at a time
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const SWEEP_BATCH: usize = 1024;
+
+/// The KV store served over a `Transport`, to every client connected to it.
+pub struct KvServer<T: Transport> {
+    transport: T,
+    engine: Box<dyn KvEngine>,
+    // kept in sync with engine, clients read it for gets
+    index: ReadIndex,
+    // keys whose integer value lives in a counter slot instead of engine
+    counters: HashMap<String, usize>,
+    // keys of engine that expire, they are left out of index so that every
+    // get of them is checked here. They never become counters
+    expiry: Expiry,
+    last_sweep: Instant,
+    // counter slots never handed out, see retire_counter
+    unused_counters: VecDeque<usize>,
+    // the messages of the page each connection is being sent, until it
+    // sends a request other than More
+    streams: HashMap<usize, VecDeque<Vec<u8>>>,
+    // every change of engine is appended to it first
+    wal: Option<Wal>,
+    snapshots: Option<Snapshots>,
+}
+
+impl<T: Transport> KvServer<T> {
+    /// Serve the values in `engine`, which expire as `expiry` says. Those
+    /// that do not are indexed for the clients.
+    pub fn new(mut transport: T, engine: Box<dyn KvEngine>, expiry: Expiry) -> Result<Self> {
+        let mut index = ReadIndex::new(&transport);
+        for (key, value) in engine.scan(Bound::Unbounded, usize::MAX) {
+            match expiry.deadline(&key) {
+                Some(_) => index.exclude(&mut transport, &key)?,
+                None => index.set(&mut transport, &key, &value)?,
+            }
+        }
+        Ok(KvServer {
+            index,
+            counters: HashMap::new(),
+            expiry,
+            last_sweep: Instant::now(),
+            unused_counters: (0..transport.counter_slots()).collect(),
+            streams: HashMap::new(),
+            wal: None,
+            snapshots: None,
+            transport,
+            engine,
+        })
+    }
+
+    /// Log every change to `wal`, which `engine` was replayed from.
+    ///
+    /// No key becomes a counter then: the clients change those with RDMA
+    /// atomics the server never sees, so they could not be logged.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        if !self.unused_counters.is_empty() {
+            warn!(
+                "Ignoring the {} counter slots, counters go through the server with a write-ahead log",
+                self.unused_counters.len()
+            );
+            self.unused_counters.clear();
+        }
+        self.wal = Some(wal);
+        self
+    }
+
+    /// Save snapshots with `snapshots`, the WAL is cut back after each one.
+    pub fn with_snapshots(mut self, snapshots: Snapshots) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// Accept clients, once what the store already holds is indexed.
+    pub fn listen(&mut self, config: &RdmaOpt) -> Result<()> {
+        self.transport.listen(config)
+    }
+
+    /// Serve requests until the CQ or the index fails, the transport must be
+    /// listening.
+    ///
+    /// Clients write their request with `IBV_WR_RDMA_WRITE_WITH_IMM`, so each
+    /// one consumes a receive posted here and shows up as a completion: its
+    /// queue pair tells the connection the request landed in, the immediate
+    /// data its length. Every request is answered with a send. A client whose
+    /// work request fails is disconnected, the others are not affected.
+    /// Between completions, and every `SWEEP_INTERVAL` when there are none,
+    /// snapshots are started and finished and expired keys swept. A key that
+    /// expired is gone for the requests either way.
+    pub fn process_kv_opt(&mut self) -> Result<()> {
+        loop {
+            // an idle server still sweeps, and snapshots on its timer
+            let wc = self.transport.poll_completion_timeout(SWEEP_INTERVAL)?;
+            self.tick_snapshots();
+            if self.last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.sweep(SWEEP_BATCH)?;
+                self.last_sweep = Instant::now();
+            }
+            let Some(wc) = wc else {
+                continue;
+            };
+            let i = wc.qp;
+            if let Err(e) = wc.check() {
+                warn!("Dropping client {}: {}", i, e);
+                self.drop_client(i);
+                continue;
+            }
+            if wc.opcode != ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM {
+                // the sends of earlier replies
+                debug!("Ignored completion {:?}", wc);
+                continue;
+            }
+            if let Err(e) = self.serve(wc) {
+                warn!("Dropping client {}: {}", i, e);
+                self.drop_client(i);
+            }
+        }
+    }
+
+    fn drop_client(&mut self, i: usize) {
+        self.streams.remove(&i);
+        self.transport.disconnect(i);
+    }
+
+    // answer the request that completed with wc
+    fn serve(&mut self, wc: Completion) -> Result<()> {
+        let i = wc.qp;
+        self.transport.check_the_buf(i);
+        let reply = match self.read_request(wc) {
+            Ok(kv_opt) => self.respond(i, kv_opt)?,
+            Err(e) => {
+                error!("Invalid request in buf {}: {}", i, e);
+                serde_json::to_vec(&KvReply::Err(e.to_string()))?
+            }
+        };
+        // the client may write its next request as soon as it has the
+        // reply, the receive for it has to be there first
+        self.transport.post_receive(i)?;
+        self.reply(&reply, i)
+    }
+
+    fn read_request(&self, wc: Completion) -> Result<KeyValueOpt> {
+        let payload = self.transport.read_msg(wc.qp)?;
+        if wc.imm_data != Some((FRAME_HEADER_SIZE + payload.len()) as u32) {
+            return Err(RdmaKvError::Protocol(format!(
+                "frame of {} bytes does not match the {:?} bytes written",
+                FRAME_HEADER_SIZE + payload.len(),
+                wc.imm_data
+            )));
+        }
+        Ok(serde_json::from_slice(&payload)?)
+    }
+
+    // the reply to kv_opt from connection i, serialized
+    fn respond(&mut self, i: usize, kv_opt: KeyValueOpt) -> Result<Vec<u8>> {
+        if !matches!(kv_opt, KeyValueOpt::More) {
+            self.streams.remove(&i);
+        }
+        if kv_opt.is_scan() {
+            // a page holds no expired keys, and as many as the others allow
+            self.sweep(usize::MAX)?;
+        }
+        let page = match kv_opt {
+            KeyValueOpt::Scan {
+                start,
+                end,
+                limit,
+                cursor,
+            } => {
+                let from = page_start(start.as_deref(), cursor.as_deref());
+                self.page(from, limit, |key| {
+                    end.as_deref().is_none_or(|end| key < end)
+                })?
+            }
+            KeyValueOpt::Prefix {
+                prefix,
+                limit,
+                cursor,
+            } => {
+                let from = page_start(Some(&prefix), cursor.as_deref());
+                self.page(from, limit, |key| key.starts_with(prefix.as_str()))?
+            }
+            KeyValueOpt::More => {
+                return match self.streams.get_mut(&i).and_then(VecDeque::pop_front) {
+                    Some(chunk) => Ok(chunk),
+                    None => Ok(serde_json::to_vec(&PageReply::Err(
+                        "no page is being sent".to_string(),
+                    ))?),
+                };
+            }
+            kv_opt => return Ok(serde_json::to_vec(&self.handle(kv_opt))?),
+        };
+        let mut chunks = match chunks(page, self.transport.buf_size(i)) {
+            Ok(chunks) => chunks,
+            Err(e) => return Ok(serde_json::to_vec(&PageReply::Err(e))?),
+        };
+        let first = chunks.pop_front().unwrap();
+        if !chunks.is_empty() {
+            self.streams.insert(i, chunks);
+        }
+        Ok(first)
+    }
+
+    // up to limit entries from from on, as long as within holds for their
+    // keys. Counters are merged in, the engine does not hold them
+    fn page(&self, from: Bound<&str>, limit: usize, within: impl Fn(&str) -> bool) -> Result<Page> {
+        let limit = limit.clamp(1, MAX_PAGE_LIMIT);
+        let mut entries = self.engine.scan(from, limit + 1);
+        for (key, &slot) in &self.counters {
+            if engine::after(from, key) {
+                entries.push((key.clone(), self.counter_value(slot)?.to_string()));
+            }
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .take_while(|(key, _)| within(key))
+            .take(limit + 1)
+            .collect();
+        // the extra entry only tells whether there is a next page
+        let cursor = (entries.len() > limit).then(|| {
+            entries.truncate(limit);
+            entries[limit - 1].0.clone()
+        });
+        Ok(Page { entries, cursor })
+    }
+
+    fn handle(&mut self, kv_opt: KeyValueOpt) -> KvReply {
+        if let Some(key) = kv_opt.key() {
+            if self.expiry.expired(key, expiry::now()) {
+                self.expiry.clear(key);
+                self.forget(key).map_err(reported)?;
+            }
+        }
+        match kv_opt {
+            KeyValueOpt::Set { key, value, ttl } => {
+                let deadline = match ttl {
+                    Some(0) => return Err("the ttl is 0 seconds".to_string()),
+                    Some(ttl) => Some(deadline_in(ttl)),
+                    None => None,
+                };
+                self.log(&match deadline {
+                    Some(deadline) => Record::SetExpiring {
+                        key: &key,
+                        value: &value,
+                        deadline,
+                    },
+                    None => Record::Set {
+                        key: &key,
+                        value: &value,
+                    },
+                })?;
+                self.retire_counter(&key);
+                match deadline {
+                    Some(deadline) => self.expiry.set(&key, deadline),
+                    None => {
+                        self.expiry.clear(&key);
+                    }
+                }
+                self.publish(&key, &value).map_err(reported)?;
+                self.engine.set(key, value);
+                debug!("kv store: {:?}", self.engine.stats());
+                Ok(None)
+            }
+            KeyValueOpt::Get { key } => Ok(Some(
+                self.value(&key)
+                    .map_err(reported)?
+                    .unwrap_or_else(|| KEY_NOT_FOUND.to_string()),
+            )),
+            KeyValueOpt::Delete { key } => {
+                self.log(&Record::Delete { key: &key })?;
+                self.retire_counter(&key);
+                self.expiry.clear(&key);
+                self.forget(&key).map_err(reported)?;
+                debug!("kv store: {:?}", self.engine.stats());
+                Ok(None)
+            }
+            KeyValueOpt::Incr { key } => self.add(&key, 1).map(|value| Some(value.to_string())),
+            KeyValueOpt::Decr { key } => self.add(&key, -1).map(|value| Some(value.to_string())),
+            KeyValueOpt::CompareAndSwap { key, expected, new } => self
+                .compare_and_swap(&key, expected, new)
+                .map(|value| Some(value.to_string())),
+            KeyValueOpt::Expire { key, seconds } => self
+                .expire(&key, seconds)
+                .map(|set| Some((set as i64).to_string())),
+            KeyValueOpt::Persist { key } => self
+                .persist(&key)
+                .map(|cleared| Some((cleared as i64).to_string())),
+            KeyValueOpt::Ttl { key } => self.ttl(&key).map(|ttl| Some(ttl.to_string())),
+            KeyValueOpt::Save => self
+                .save()
+                .map(|()| Some("saving a snapshot in the background".to_string())),
+            KeyValueOpt::Scan { .. } | KeyValueOpt::Prefix { .. } | KeyValueOpt::More => {
+                Err("pages are sent in chunks".to_string())
+            }
+        }
+    }
+
+    // index key unless it expires, then clients have to ask
+    fn publish(&mut self, key: &str, value: &str) -> Result<()> {
+        match self.expiry.deadline(key) {
+            Some(_) => self.index.exclude(&mut self.transport, key),
+            None => self.index.set(&mut self.transport, key, value),
+        }
+    }
+
+    // drop key from the index and the engine, but not its deadline
+    fn forget(&mut self, key: &str) -> Result<()> {
+        self.index.remove(&mut self.transport, key)?;
+        self.engine.delete(key);
+        Ok(())
+    }
+
+    // reap up to limit expired keys. Not logged: their deadlines are, a
+    // replay reaps them again
+    fn sweep(&mut self, limit: usize) -> Result<()> {
+        let keys = self.expiry.pop_expired(expiry::now(), limit);
+        if !keys.is_empty() {
+            debug!("{} keys expired", keys.len());
+        }
+        for key in keys {
+            self.forget(&key)?;
+        }
+        Ok(())
+    }
+
+    // let key expire in seconds, false when it does not exist
+    fn expire(&mut self, key: &str, seconds: u64) -> std::result::Result<bool, String> {
+        let Some(value) = self.value(key).map_err(reported)? else {
+            return Ok(false);
+        };
+        let deadline = deadline_in(seconds);
+        if self.counters.contains_key(key) {
+            // the atomics of the clients would outlive the deadline, the
+            // counter becomes a plain value
+            self.log(&Record::SetExpiring {
+                key,
+                value: &value,
+                deadline,
+            })?;
+            self.retire_counter(key);
+            self.engine.set(key.to_string(), value);
+        } else {
+            self.log(&Record::Expire { key, deadline })?;
+        }
+        self.expiry.set(key, deadline);
+        self.index
+            .exclude(&mut self.transport, key)
+            .map_err(reported)?;
+        Ok(true)
+    }
+
+    // keep key from expiring, false when it was not going to
+    fn persist(&mut self, key: &str) -> std::result::Result<bool, String> {
+        if self.expiry.deadline(key).is_none() {
+            return Ok(false);
+        }
+        self.log(&Record::Persist { key })?;
+        self.expiry.clear(key);
+        if let Some(value) = self.engine.get(key) {
+            self.index
+                .set(&mut self.transport, key, &value)
+                .map_err(reported)?;
+        }
+        Ok(true)
+    }
+
+    // seconds until key expires, rounded up, -1 when it does not and -2
+    // when it does not exist
+    fn ttl(&self, key: &str) -> std::result::Result<i64, String> {
+        Ok(
+            match (
+                self.value(key).map_err(reported)?,
+                self.expiry.deadline(key),
+            ) {
+                (None, _) => -2,
+                (Some(_), None) => -1,
+                (Some(_), Some(deadline)) => {
+                    deadline.saturating_sub(expiry::now()).div_ceil(1000) as i64
+                }
+            },
+        )
+    }
+
+    // the record of setting key to value, which keeps its deadline
+    fn set_record<'a>(&self, key: &'a str, value: &'a str) -> Record<'a> {
+        match self.expiry.deadline(key) {
+            Some(deadline) => Record::SetExpiring {
+                key,
+                value,
+                deadline,
+            },
+            None => Record::Set { key, value },
+        }
+    }
+
+    // append record to the WAL, before the change is made
+    fn log(&mut self, record: &Record) -> std::result::Result<(), String> {
+        match &mut self.wal {
+            Some(wal) => wal.append(record).map_err(reported),
+            None => Ok(()),
+        }
+    }
+
+    // start a snapshot of what the store holds now, it is written while
+    // requests are served
+    fn save(&mut self) -> std::result::Result<(), String> {
+        if self.snapshots.is_none() {
+            return Err("the server saves no snapshots, see --snapshot".to_string());
+        }
+        self.sweep(usize::MAX).map_err(reported)?;
+        let mut entries: Vec<_> = self
+            .engine
+            .scan(Bound::Unbounded, usize::MAX)
+            .into_iter()
+            .map(|(key, value)| {
+                let deadline = self.expiry.deadline(&key);
+                (key, value, deadline)
+            })
+            .collect();
+        for (key, &slot) in &self.counters {
+            let value = self.counter_value(slot).map_err(reported)?;
+            entries.push((key.clone(), value.to_string(), None));
+        }
+        let covered = self.wal.as_ref().map(Wal::position);
+        self.snapshots.as_mut().unwrap().start(entries, covered)
+    }
+
+    // drop what a snapshot that was saved holds from the WAL, and start the
+    // next one when it is due
+    fn tick_snapshots(&mut self) {
+        let Some(snapshots) = &mut self.snapshots else {
+            return;
+        };
+        match snapshots.finished() {
+            Some((Ok(()), covered)) => {
+                info!("Saved a snapshot to {}", snapshots.path().display());
+                if let (Some(wal), Some(position)) = (&mut self.wal, covered) {
+                    if let Err(e) = wal.drop_before(position) {
+                        error!("{}", e);
+                    }
+                }
+            }
+            Some((Err(e), _)) => error!("Saving a snapshot failed: {}", e),
+            None => {}
+        }
+        if snapshots.due() {
+            if let Err(e) = self.save() {
+                warn!("Snapshot not started: {}", e);
+            }
+        }
+    }
+
+    fn value(&self, key: &str) -> Result<Option<String>> {
+        match self.counters.get(key) {
+            Some(&slot) => Ok(Some(self.counter_value(slot)?.to_string())),
+            None => Ok(self.engine.get(key)),
+        }
+    }
+
+    fn counter_value(&self, slot: usize) -> Result<i64> {
+        Ok(self.transport.counter(slot)?.load(Ordering::SeqCst) as i64)
+    }
+
+    // the integer value of a key that is not a counter, a missing key is 0
+    fn integer(&self, key: &str) -> std::result::Result<i64, String> {
+        match self.engine.get(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("value of {} is not an integer", key)),
+            None => Ok(0),
+        }
+    }
+
+    // a counter changed here races with the atomics of the clients, which
+    // is why the transport only exposes counter slots on HCAs with
+    // IBV_ATOMIC_GLOB. Clients only ask the server about counters they have
+    // not seen in the index yet, so this stays rare
+    fn add(&mut self, key: &str, delta: i64) -> std::result::Result<i64, String> {
+        if let Some(&slot) = self.counters.get(key) {
+            let old = self
+                .transport
+                .counter(slot)
+                .map_err(reported)?
+                .fetch_add(delta as u64, Ordering::SeqCst);
+            return Ok((old as i64).wrapping_add(delta));
+        }
+        let value = self.integer(key)?.wrapping_add(delta);
+        self.store_integer(key, value)?;
+        Ok(value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: i64,
+        new: i64,
+    ) -> std::result::Result<i64, String> {
+        if let Some(&slot) = self.counters.get(key) {
+            return Ok(
+                match self
+                    .transport
+                    .counter(slot)
+                    .map_err(reported)?
+                    .compare_exchange(
+                        expected as u64,
+                        new as u64,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    ) {
+                    Ok(old) | Err(old) => old as i64,
+                },
+            );
+        }
+        let old = self.integer(key)?;
+        if old == expected {
+            self.store_integer(key, new)?;
+        }
+        Ok(old)
+    }
+
+    // turn key into a counter that clients can change with atomics, or keep
+    // it a plain value when no slot is free or it cannot be indexed
+    fn store_integer(&mut self, key: &str, value: i64) -> std::result::Result<(), String> {
+        // never taken with a WAL, see with_wal
+        let slot = match self.expiry.deadline(key) {
+            Some(_) => None,
+            None => self.unused_counters.pop_front(),
+        };
+        if let Some(slot) = slot {
+            self.transport
+                .counter(slot)
+                .map_err(reported)?
+                .store(value as u64, Ordering::SeqCst);
+            if self
+                .index
+                .set_counter(&mut self.transport, key, slot)
+                .map_err(reported)?
+            {
+                self.engine.delete(key);
+                self.counters.insert(key.to_string(), slot);
+                debug!("{} lives in counter slot {}", key, slot);
+                return Ok(());
+            }
+            self.unused_counters.push_front(slot);
+        }
+        let value = value.to_string();
+        self.log(&self.set_record(key, &value))?;
+        self.publish(key, &value).map_err(reported)?;
+        self.engine.set(key.to_string(), value);
+        Ok(())
+    }
+
+    // key is no longer a counter. Its slot is never handed out again: a
+    // client that looked the counter up before may still post an atomic on
+    // it, which must not change another key
+    fn retire_counter(&mut self, key: &str) {
+        if let Some(slot) = self.counters.remove(key) {
+            debug!("Counter slot {} of {} is retired", slot, key);
+        }
+    }
+
+    // the completion of the send is not waited for, the buffer is only
+    // written again by the client's next request
+    fn reply(&mut self, send_str: &[u8], i: usize) -> Result<()> {
+        let len = match self.transport.write_msg(send_str, i) {
+            Ok(len) => len,
+            Err(e) => {
+                // still answer, the client is waiting for a reply
+                warn!("Reply in buf {} dropped: {}", i, e);
+                let send_str = serde_json::to_vec(&KvReply::Err(e.to_string()))?;
+                self.transport.write_msg(&send_str, i)?
+            }
+        };
+        self.transport
+            .post_send(ibv_wr_opcode::IBV_WR_SEND, i, len)?;
+        Ok(())
+    }
+}
+
+// what a client is told of e, which is logged here
+fn reported(e: RdmaKvError) -> String {
+    error!("{}", e);
+    e.to_string()
+}
+
+// the deadline of a key that expires in seconds
+fn deadline_in(seconds: u64) -> u64 {
+    expiry::now().saturating_add(seconds.saturating_mul(1000))
+}
+
+// where a page starts: after the cursor, but not before start
+fn page_start<'a>(start: Option<&'a str>, cursor: Option<&'a str>) -> Bound<&'a str> {
+    match (start, cursor) {
+        (Some(start), Some(cursor)) if cursor < start => Bound::Included(start),
+        (_, Some(cursor)) => Bound::Excluded(cursor),
+        (Some(start), None) => Bound::Included(start),
+        (None, None) => Bound::Unbounded,
+    }
+}
+
+// split page into messages that each fit in a buffer of buf_size bytes,
+// fails when a single entry does not
+fn chunks(page: Page, buf_size: usize) -> std::result::Result<VecDeque<Vec<u8>>, String> {
+    let max = buf_size.saturating_sub(FRAME_HEADER_SIZE);
+    let encode = |chunk: &PageChunk| serde_json::to_vec(&Ok::<_, ()>(chunk)).unwrap();
+    // the last chunk, empty, is the largest any chunk is besides its entries
+    let overhead = encode(&PageChunk {
+        entries: Vec::new(),
+        cursor: page.cursor.clone(),
+        more: false,
+    })
+    .len();
+    let mut chunks = VecDeque::new();
+    let mut chunk = PageChunk {
+        more: true,
+        ..Default::default()
+    };
+    let mut len = overhead;
+    for entry in page.entries {
+        // the entry and the comma before it
+        let entry_len = serde_json::to_vec(&entry).unwrap().len() + 1;
+        if overhead + entry_len > max {
+            return Err(format!(
+                "the entry of {:?} does not fit in a message of {} bytes",
+                entry.0, max
+            ));
+        }
+        if len + entry_len > max {
+            chunks.push_back(encode(&chunk));
+            chunk.entries.clear();
+            len = overhead;
+        }
+        chunk.entries.push(entry);
+        len += entry_len;
+    }
+    chunk.cursor = page.cursor;
+    chunk.more = false;
+    chunks.push_back(encode(&chunk));
+    Ok(chunks)
+}
@@ -0,0 +1,210 @@
+//! Snapshots of the whole store, written while requests are served.
+//!
+//! A snapshot file is `MAGIC`, the CRC-32 of the payload, little endian,
+//! and the bincode payload with varints: every key, its value and its
+//! deadline. It replaces the previous snapshot only once it is on disk.
+use crate::engine::KvEngine;
+use crate::error::{RdmaKvError, Result};
+use crate::expiry::Expiry;
+use crate::wal;
+use bincode::Options;
+use std::{
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// "RKVSNP" and the version of the format.
+const MAGIC: [u8; 8] = *b"RKVSNP02";
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+
+/// A key, its value and when it expires, see `expiry::now`.
+pub type Entry = (String, String, Option<u64>);
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Load the snapshot at `path` into `engine` and `expiry`, returns the
+/// number of keys.
+pub fn restore(path: &Path, engine: &mut dyn KvEngine, expiry: &mut Expiry) -> Result<usize> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| RdmaKvError::storage(format!("reading {}", path.display()), e))?;
+    let corrupt = |what: &str| {
+        RdmaKvError::InvalidInput(format!("{} is not a snapshot: {}", path.display(), what))
+    };
+    if bytes.len() < HEADER_SIZE || bytes[..MAGIC.len()] != MAGIC {
+        return Err(corrupt("it does not start with the magic"));
+    }
+    let crc = u32::from_le_bytes(bytes[MAGIC.len()..HEADER_SIZE].try_into().unwrap());
+    let payload = &bytes[HEADER_SIZE..];
+    if wal::crc32(payload) != crc {
+        return Err(corrupt("its checksum does not match"));
+    }
+    let entries: Vec<Entry> = options()
+        .deserialize(payload)
+        .map_err(|e| corrupt(&e.to_string()))?;
+    let keys = entries.len();
+    for (key, value, deadline) in entries {
+        if let Some(deadline) = deadline {
+            expiry.set(&key, deadline);
+        }
+        engine.set(key, value);
+    }
+    Ok(keys)
+}
+
+/// Write `entries` to a snapshot at `path`.
+pub fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let payload = options().serialize(entries)?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&wal::crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    wal::replace_file(path, &bytes)
+        .map_err(|e| RdmaKvError::storage(format!("writing {}", path.display()), e))
+}
+
+/// The snapshots a `KvServer` saves, on a `Save` and on a timer.
+pub struct Snapshots {
+    path: PathBuf,
+    interval: Option<Duration>,
+    last: Instant,
+    // the snapshot being written, and the WAL position it covers
+    saving: Option<(JoinHandle<Result<()>>, Option<u64>)>,
+}
+
+impl Snapshots {
+    /// Snapshots to `path`, also every `interval` when there is one.
+    pub fn new(path: PathBuf, interval: Option<Duration>) -> Self {
+        Snapshots {
+            path,
+            interval,
+            last: Instant::now(),
+            saving: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the timer asks for a snapshot.
+    pub fn due(&self) -> bool {
+        self.saving.is_none()
+            && self
+                .interval
+                .is_some_and(|interval| self.last.elapsed() >= interval)
+    }
+
+    /// Write `entries` in the background. `covered` is the position of the
+    /// WAL they hold every record before.
+    pub fn start(
+        &mut self,
+        entries: Vec<Entry>,
+        covered: Option<u64>,
+    ) -> std::result::Result<(), String> {
+        if self.saving.is_some() {
+            return Err("a snapshot is being saved already".to_string());
+        }
+        let path = self.path.clone();
+        let handle = std::thread::spawn(move || write(&path, &entries));
+        self.saving = Some((handle, covered));
+        self.last = Instant::now();
+        Ok(())
+    }
+
+    /// How the snapshot being written went, once it is done, and the WAL
+    /// position it covers.
+    pub fn finished(&mut self) -> Option<(Result<()>, Option<u64>)> {
+        if !self.saving.as_ref()?.0.is_finished() {
+            return None;
+        }
+        let (handle, covered) = self.saving.take()?;
+        let res = handle.join().unwrap_or_else(|_| {
+            Err(RdmaKvError::Store(
+                "the thread saving the snapshot panicked".to_string(),
+            ))
+        });
+        Some((res, covered))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::EngineKind;
+    use crate::engine;
+    use crate::temp_dir::TempDir;
+
+    fn restored(path: &Path) -> Result<(Box<dyn KvEngine>, Expiry, usize)> {
+        let mut engine = engine::new(EngineKind::Btree);
+        let mut expiry = Expiry::default();
+        let keys = restore(path, engine.as_mut(), &mut expiry)?;
+        Ok((engine, expiry, keys))
+    }
+
+    #[test]
+    fn write_and_restore() {
+        let dir = TempDir::new("snapshot-restore");
+        let path = dir.join("snapshot");
+        let entries = vec![
+            ("a".to_string(), "1".to_string(), None),
+            ("b".to_string(), "".to_string(), Some(u64::MAX)),
+            ("c".to_string(), "3".repeat(300), Some(1)),
+        ];
+        write(&path, &entries).unwrap();
+        let (engine, expiry, keys) = restored(&path).unwrap();
+        assert_eq!(keys, 3);
+        let all = engine.scan(std::ops::Bound::Unbounded, usize::MAX);
+        let deadlines: Vec<_> = all
+            .into_iter()
+            .map(|(key, value)| {
+                let deadline = expiry.deadline(&key);
+                (key, value, deadline)
+            })
+            .collect();
+        assert_eq!(deadlines, entries);
+
+        // a new snapshot replaces the old one
+        write(&path, &entries[..1]).unwrap();
+        assert_eq!(restored(&path).unwrap().2, 1);
+        assert!(!dir.join("snapshot.tmp").exists());
+    }
+
+    #[test]
+    fn bad_snapshots_are_rejected() {
+        let dir = TempDir::new("snapshot-bad");
+        let path = dir.join("snapshot");
+        write(&path, &[("a".to_string(), "1".to_string(), None)]).unwrap();
+        let good = std::fs::read(&path).unwrap();
+        let mut flipped = good.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let mut foreign = good.clone();
+        foreign[..MAGIC.len()].copy_from_slice(b"RKVWAL01");
+        // the checksum matches, the payload is not a snapshot
+        let mut garbage = MAGIC.to_vec();
+        garbage.extend_from_slice(&wal::crc32(&[0xff; 3]).to_le_bytes());
+        garbage.extend_from_slice(&[0xff; 3]);
+        let cases = [
+            (flipped, "checksum"),
+            (foreign, "magic"),
+            (good[..HEADER_SIZE - 1].to_vec(), "magic"),
+            (Vec::new(), "magic"),
+            (garbage, "not a snapshot"),
+        ];
+        for (bytes, expected) in cases {
+            std::fs::write(&path, &bytes).unwrap();
+            match restored(&path) {
+                Err(RdmaKvError::InvalidInput(msg)) => {
+                    assert!(msg.contains(expected), "{}", msg)
+                }
+                other => panic!("{} was restored: {:?}", expected, other.map(|r| r.2)),
+            }
+        }
+        assert!(matches!(
+            restored(&dir.join("missing")),
+            Err(RdmaKvError::Storage { .. })
+        ));
+    }
+}
@@ -0,0 +1,27 @@
+//! A directory for the tests that write files.
+use std::{fs, path::PathBuf};
+
+/// A fresh directory under the system one, removed with its files when it
+/// is dropped, also when the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` tells the directories of the tests apart.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rdma-kv-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// The path of `file` in the directory.
+    pub fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::cli::RdmaOpt;
+use crate::error::{RdmaKvError, Result};
+use crate::events::TransportStatus;
+use crate::frame;
+use rdma_sys::{ibv_wc_opcode, ibv_wc_status, ibv_wr_opcode};
+use std::{future::Future, sync::atomic::AtomicU64, time::Duration};
+use tracing::{debug, info, warn};
+
+/// A finished work request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    /// The id returned when the request was posted.
+    pub wr_id: u64,
+    /// Index of the queue pair, and buffer, the request was posted on.
+    pub qp: usize,
+    /// `IBV_WC_SUCCESS`, or why the request failed. The other fields but
+    /// `wr_id` and `qp` are only valid on success.
+    pub status: ibv_wc_status::Type,
+    pub opcode: ibv_wc_opcode::Type,
+    pub byte_len: u32,
+    /// Immediate data of a `*_WITH_IMM` request, in host byte order.
+    pub imm_data: Option<u32>,
+}
+
+impl Completion {
+    /// Fails if the request did not succeed.
+    pub fn check(&self) -> Result<()> {
+        if self.status == ibv_wc_status::IBV_WC_SUCCESS {
+            Ok(())
+        } else {
+            Err(RdmaKvError::Completion {
+                qp: self.qp,
+                wr_id: self.wr_id,
+                status: self.status,
+            })
+        }
+    }
+}
+
+/// An RDMA atomic, or read, of a counter slot exposed by the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterOp {
+    Read,
+    FetchAdd(u64),
+    CompareSwap { compare: u64, swap: u64 },
+}
+
+/// Build the id of the `seq`th work request posted on queue pair `i`, the
+/// queue pair a completion belongs to can then be read back from its id.
+pub fn make_wr_id(i: usize, seq: u32) -> u64 {
+    ((i as u64) << 32) | seq as u64
+}
+
+/// The queue pair index encoded by `make_wr_id`.
+pub fn wr_id_qp(wr_id: u64) -> usize {
+    (wr_id >> 32) as usize
+}
+
+/// The operations the KV store needs from the fabric.
+///
+/// Every connection owns one registered buffer, addressed by its index `i`.
+/// A server listens and connections come and go while it runs, a client
+/// connects to its server and talks to it through connection 0.
+/// A server may also expose an index region that clients only read, see
+/// `index::ReadIndex`, and counter slots that clients change with atomics.
+/// `RdmaContext` implements this on top of ibverbs, `Loopback` implements it
+/// in memory so that server and client can run in the same process without
+/// an HCA.
+pub trait Transport {
+    /// Usable size of buffer `i`, the smaller of the local and the remote
+    /// buffer once connected.
+    fn buf_size(&self, i: usize) -> usize;
+
+    /// Connect to the server of `config` as connection 0, exchanging the
+    /// connection data and bringing the queue pair up so that work requests
+    /// can be posted.
+    fn connect(&mut self, config: &RdmaOpt) -> Result<()>;
+
+    /// Bring connection 0 back after its queue pair failed: reset it and
+    /// redo the exchange with the server of `config`, connecting anew if it
+    /// is gone.
+    fn reconnect(&mut self, config: &RdmaOpt) -> Result<()>;
+
+    /// Same as `reconnect`, but the exchange with the server, which blocks
+    /// on sockets, does not block the tokio runtime.
+    fn reconnect_async(&mut self, config: &RdmaOpt) -> impl Future<Output = Result<()>> + Send;
+
+    /// Accept clients in the background from now on. Each one gets a new
+    /// connection whose first receive is posted before the client can
+    /// write, and which is dropped again when the client goes away.
+    fn listen(&mut self, config: &RdmaOpt) -> Result<()>;
+
+    /// Drop connection `i`, doing nothing if it is gone already. Work
+    /// requests it still had outstanding are flushed.
+    fn disconnect(&mut self, i: usize);
+
+    /// Post `opcode` with the first `len` bytes of local buffer `i`. Writes
+    /// and reads target the remote buffer advertised by the peer, the
+    /// `*_WITH_IMM` opcodes carry `len` as immediate data so that the peer
+    /// learns how much arrived. Returns the wr_id its completion will carry.
+    fn post_send(&mut self, opcode: ibv_wr_opcode::Type, i: usize, len: usize) -> Result<u64>;
+
+    /// Post an RDMA READ of `len` bytes at `offset` of the index region
+    /// advertised by the peer into local buffer `i`. Returns its wr_id.
+    fn post_read_index(&mut self, i: usize, offset: usize, len: usize) -> Result<u64>;
+
+    /// Post `op` on counter slot `slot` of the peer, the value the slot held
+    /// before lands in the first 8 bytes of local buffer `i`, see
+    /// `read_counter_result`. Returns its wr_id.
+    fn post_counter_op(&mut self, i: usize, slot: usize, op: CounterOp) -> Result<u64>;
+
+    /// Post a receive into local buffer `i`, returns its wr_id.
+    fn post_receive(&mut self, i: usize) -> Result<u64>;
+
+    /// Block until the next work request completes.
+    fn poll_completion(&self) -> Result<Completion>;
+
+    /// Like `poll_completion`, but gives up after `timeout` with `Ok(None)`.
+    fn poll_completion_timeout(&self, timeout: Duration) -> Result<Option<Completion>>;
+
+    /// Wait for the next completion without blocking the tokio runtime.
+    fn poll_completion_async(&self) -> impl Future<Output = Result<Completion>> + Send;
+
+    /// Poll completions until every request in `wr_ids` has finished,
+    /// completions of other requests are dropped. Fails as soon as one of
+    /// `wr_ids` failed.
+    fn wait_for(&self, wr_ids: &mut Vec<u64>) -> Result<()> {
+        while !wr_ids.is_empty() {
+            let wc = self.poll_completion()?;
+            if remove_wr_id(wr_ids, wc) {
+                wc.check()?;
+            } else {
+                debug!("Ignored completion {:?}", wc);
+            }
+        }
+        Ok(())
+    }
+
+    /// Async version of `wait_for`.
+    fn wait_for_async(&self, wr_ids: &mut Vec<u64>) -> impl Future<Output = Result<()>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            while !wr_ids.is_empty() {
+                let wc = self.poll_completion_async().await?;
+                if remove_wr_id(wr_ids, wc) {
+                    wc.check()?;
+                } else {
+                    debug!("Ignored completion {:?}", wc);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Copy out the current content of registered buffer `i`.
+    fn read_buf(&self, i: usize) -> Vec<u8>;
+
+    /// Overwrite the start of registered buffer `i` with `bytes`.
+    fn write_buf(&mut self, bytes: &[u8], i: usize);
+
+    /// Size of the local index region, 0 when none is exposed.
+    fn index_size(&self) -> usize;
+
+    /// Overwrite the local index region at `offset` with `bytes`, fails when
+    /// they do not fit in it.
+    fn write_index(&mut self, bytes: &[u8], offset: usize) -> Result<()>;
+
+    /// Size of the index region advertised by the peer of connection `i`,
+    /// 0 when it is gone.
+    fn remote_index_size(&self, i: usize) -> usize;
+
+    /// Number of local counter slots, 0 when none are exposed.
+    fn counter_slots(&self) -> usize;
+
+    /// Local counter slot `slot`, the peer changes it with RDMA atomics.
+    /// Only exposed when those are atomic with the CPU's own, so both may
+    /// change it. Fails when there is no such slot.
+    fn counter(&self, slot: usize) -> Result<&AtomicU64>;
+
+    /// Number of counter slots advertised by the peer of connection `i`, 0
+    /// when it is gone.
+    fn remote_counter_slots(&self, i: usize) -> usize;
+
+    /// State of the port and of every connection, with the async events
+    /// that explain why one of them went down.
+    fn status(&self) -> TransportStatus;
+
+    /// The value returned by the last `post_counter_op` on buffer `i`.
+    fn read_counter_result(&self, i: usize) -> u64 {
+        u64::from_ne_bytes(self.read_buf(i)[..8].try_into().unwrap())
+    }
+
+    /// Frame `payload` into buffer `i`, fails if it does not fit. Returns
+    /// the length of the frame.
+    fn write_msg(&mut self, payload: &[u8], i: usize) -> Result<usize> {
+        let frame = frame::encode(payload, self.buf_size(i))?;
+        self.write_buf(&frame, i);
+        debug!("buf {} holds a message of {} bytes", i, payload.len());
+        Ok(frame.len())
+    }
+
+    /// Payload of the message currently in buffer `i`.
+    fn read_msg(&self, i: usize) -> Result<Vec<u8>> {
+        let buf = self.read_buf(i);
+        frame::decode(&buf[..self.buf_size(i)]).map(|payload| payload.to_vec())
+    }
+
+    fn check_the_buf(&self, i: usize) {
+        match self.read_msg(i) {
+            Ok(payload) => info!(
+                "current buf in RDMA context is: {:#?}",
+                String::from_utf8_lossy(&payload)
+            ),
+            Err(e) => warn!("current buf in RDMA context is not a message: {}", e),
+        }
+    }
+}
+
+fn remove_wr_id(wr_ids: &mut Vec<u64>, wc: Completion) -> bool {
+    let len = wr_ids.len();
+    wr_ids.retain(|&wr_id| wr_id != wc.wr_id);
+    wr_ids.len() != len
+}
@@ -0,0 +1,613 @@
+//! Owned wrappers around the ibverbs objects.
+//!
+//! Each object holds an `Arc` of the object it was created from, so a parent
+//! is only destroyed after the last of its children: a `QueuePair` keeps its
+//! `ProtectionDomain` and `CompletionQueue` alive, those keep the
+//! `DeviceContext` alive. Nothing is destroyed twice because none of them is
+//! `Clone`.
+//!
+//! libibverbs objects may be used from any thread, so every wrapper is
+//! `Send` and `Sync`.
+use crate::error::{RdmaKvError, Result};
+use crate::gid::Gid;
+use rdma_sys::*;
+use std::{
+    ffi::CStr,
+    io,
+    os::fd::RawFd,
+    sync::atomic::{AtomicU64, AtomicU8},
+    sync::Arc,
+};
+use tracing::{debug, warn};
+
+/// An opened RDMA device.
+pub struct DeviceContext {
+    ctx: *mut ibv_context,
+}
+
+unsafe impl Send for DeviceContext {}
+unsafe impl Sync for DeviceContext {}
+
+/// Names of every RDMA device, in the order ibverbs lists them.
+pub fn device_names() -> Result<Vec<String>> {
+    let mut num_devs: i32 = 0;
+    let dev_list_ptr = unsafe { ibv_get_device_list(&mut num_devs) };
+    if dev_list_ptr.is_null() {
+        return Err(RdmaKvError::device(
+            "listing devices",
+            io::Error::last_os_error(),
+        ));
+    }
+    let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as _) };
+    let names = dev_list
+        .iter()
+        .filter_map(|dev| {
+            let name = unsafe { ibv_get_device_name(*dev) };
+            (!name.is_null()).then(|| {
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        })
+        .collect();
+    unsafe { ibv_free_device_list(dev_list_ptr) };
+    Ok(names)
+}
+
+/// Size in bytes of an MTU.
+pub fn mtu_bytes(mtu: ibv_mtu::Type) -> u32 {
+    128 << mtu
+}
+
+/// Name of a port state, such as `PORT_ACTIVE`.
+pub fn port_state_str(state: ibv_port_state::Type) -> String {
+    let name = unsafe { ibv_port_state_str(state) };
+    if name.is_null() {
+        return format!("{}", state);
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl DeviceContext {
+    /// Open the device called `name`, or the first one found.
+    pub fn open(name: Option<&str>) -> Result<Arc<Self>> {
+        let mut num_devs: i32 = 0;
+        let dev_list_ptr = unsafe { ibv_get_device_list(&mut num_devs) };
+        if dev_list_ptr.is_null() {
+            return Err(RdmaKvError::device(
+                "listing devices",
+                io::Error::last_os_error(),
+            ));
+        }
+        let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as _) };
+        let dev = if let Some(dev_name_inner) = name {
+            dev_list.iter().find(|iter_dev| -> bool {
+                let name = unsafe { ibv_get_device_name(**iter_dev) };
+                if name.is_null() {
+                    return false;
+                }
+                match unsafe { CStr::from_ptr(name) }.to_str() {
+                    Ok(name) => dev_name_inner.eq(name),
+                    Err(_) => {
+                        warn!("Device name {:?} is not valid", name);
+                        false
+                    }
+                }
+            })
+        } else {
+            dev_list.first()
+        };
+        let ctx = dev.map(|dev| unsafe { ibv_open_device(*dev) });
+        let err = io::Error::last_os_error();
+        // the list is not needed anymore once the device is open
+        unsafe { ibv_free_device_list(dev_list_ptr) };
+        let name = name.unwrap_or("any device");
+        match ctx {
+            None => Err(RdmaKvError::device(
+                format!("no {} found", name),
+                io::ErrorKind::NotFound.into(),
+            )),
+            Some(ctx) if ctx.is_null() => {
+                Err(RdmaKvError::device(format!("opening {}", name), err))
+            }
+            Some(ctx) => Ok(Arc::new(DeviceContext { ctx })),
+        }
+    }
+
+    /// The name the device was listed under.
+    pub fn name(&self) -> String {
+        let name = unsafe { ibv_get_device_name((*self.ctx).device) };
+        if name.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn query_port(&self, port: u8) -> Result<ibv_port_attr> {
+        let mut port_attr = unsafe { std::mem::zeroed() };
+        let err = unsafe { ___ibv_query_port(self.ctx, port, &mut port_attr) };
+        if err != 0 {
+            return Err(RdmaKvError::device(
+                format!("querying port {}", port),
+                io::Error::from_raw_os_error(err),
+            ));
+        }
+        Ok(port_attr)
+    }
+
+    pub fn query_device(&self) -> Result<ibv_device_attr> {
+        let mut device_attr = unsafe { std::mem::zeroed::<ibv_device_attr>() };
+        let err = unsafe { ibv_query_device(self.ctx, &mut device_attr) };
+        if err != 0 {
+            return Err(RdmaKvError::device(
+                "querying attributes",
+                io::Error::from_raw_os_error(err),
+            ));
+        }
+        Ok(device_attr)
+    }
+
+    pub fn query_gid(&self, port: u8, index: i32) -> Result<Gid> {
+        let mut gid = Gid::default();
+        if unsafe { ibv_query_gid(self.ctx, port, index, gid.ffi()) } != 0 {
+            return Err(RdmaKvError::device(
+                format!("querying gid {} of port {}", index, port),
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(gid)
+    }
+}
+
+/// An async event of a device, copied out before it was acked.
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncEvent {
+    pub event_type: ibv_event_type::Type,
+    /// Number of the QP a QP event is about.
+    pub qp_num: Option<u32>,
+    /// The port a port event is about.
+    pub port: Option<u8>,
+}
+
+impl DeviceContext {
+    /// Make the async event fd non-blocking, `get_async_event` then returns
+    /// instead of waiting.
+    pub fn set_async_nonblocking(&self) -> Result<RawFd> {
+        let fd = unsafe { (*self.ctx).async_fd };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(RdmaKvError::device(
+                "making the async event fd non-blocking",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(fd)
+    }
+
+    /// Read and ack the next async event, `None` when none is pending on a
+    /// non-blocking fd.
+    pub fn get_async_event(&self) -> Result<Option<AsyncEvent>> {
+        let mut event = unsafe { std::mem::zeroed::<ibv_async_event>() };
+        if unsafe { ibv_get_async_event(self.ctx, &mut event) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(RdmaKvError::device("reading async event", err));
+        }
+        let event_type = event.event_type;
+        let mut copy = AsyncEvent {
+            event_type,
+            qp_num: None,
+            port: None,
+        };
+        match event_type {
+            ibv_event_type::IBV_EVENT_QP_FATAL
+            | ibv_event_type::IBV_EVENT_QP_REQ_ERR
+            | ibv_event_type::IBV_EVENT_QP_ACCESS_ERR
+            | ibv_event_type::IBV_EVENT_COMM_EST
+            | ibv_event_type::IBV_EVENT_SQ_DRAINED
+            | ibv_event_type::IBV_EVENT_PATH_MIG
+            | ibv_event_type::IBV_EVENT_PATH_MIG_ERR
+            | ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED => {
+                // the QP cannot be destroyed before the event is acked
+                copy.qp_num = Some(unsafe { (*event.element.qp).qp_num });
+            }
+            ibv_event_type::IBV_EVENT_PORT_ACTIVE
+            | ibv_event_type::IBV_EVENT_PORT_ERR
+            | ibv_event_type::IBV_EVENT_LID_CHANGE
+            | ibv_event_type::IBV_EVENT_PKEY_CHANGE
+            | ibv_event_type::IBV_EVENT_SM_CHANGE
+            | ibv_event_type::IBV_EVENT_CLIENT_REREGISTER
+            | ibv_event_type::IBV_EVENT_GID_CHANGE => {
+                copy.port = Some(unsafe { event.element.port_num } as u8);
+            }
+            _ => {}
+        }
+        unsafe { ibv_ack_async_event(&mut event) };
+        Ok(Some(copy))
+    }
+}
+
+impl Drop for DeviceContext {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_close_device(self.ctx) };
+        report_drop("closing device", err);
+    }
+}
+
+pub struct ProtectionDomain {
+    pd: *mut ibv_pd,
+    _ctx: Arc<DeviceContext>,
+}
+
+unsafe impl Send for ProtectionDomain {}
+unsafe impl Sync for ProtectionDomain {}
+
+impl ProtectionDomain {
+    pub fn new(ctx: &Arc<DeviceContext>) -> Result<Arc<Self>> {
+        let pd = unsafe { ibv_alloc_pd(ctx.ctx) };
+        if pd.is_null() {
+            return Err(RdmaKvError::resource(
+                "allocating PD",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(Arc::new(ProtectionDomain {
+            pd,
+            _ctx: ctx.clone(),
+        }))
+    }
+}
+
+impl Drop for ProtectionDomain {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_dealloc_pd(self.pd) };
+        report_drop("deallocating PD", err);
+    }
+}
+
+/// A completion channel, its fd is non-blocking so that it can be driven by
+/// tokio.
+pub struct CompChannel {
+    channel: *mut ibv_comp_channel,
+    _ctx: Arc<DeviceContext>,
+}
+
+unsafe impl Send for CompChannel {}
+unsafe impl Sync for CompChannel {}
+
+impl CompChannel {
+    pub fn new(ctx: &Arc<DeviceContext>) -> Result<Self> {
+        let channel = unsafe { ibv_create_comp_channel(ctx.ctx) };
+        if channel.is_null() {
+            return Err(RdmaKvError::resource(
+                "creating completion channel",
+                io::Error::last_os_error(),
+            ));
+        }
+        let channel = CompChannel {
+            channel,
+            _ctx: ctx.clone(),
+        };
+        let fd = channel.fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(RdmaKvError::resource(
+                "making the completion channel non-blocking",
+                io::Error::last_os_error(),
+            ));
+        }
+        debug!("Completion channel was created, fd={}", fd);
+        Ok(channel)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        unsafe { (*self.channel).fd }
+    }
+}
+
+impl Drop for CompChannel {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_destroy_comp_channel(self.channel) };
+        report_drop("destroying completion channel", err);
+    }
+}
+
+/// A completion queue, optionally delivering its events on a channel.
+pub struct CompletionQueue {
+    cq: *mut ibv_cq,
+    // dropped after the CQ, which must go first
+    channel: Option<CompChannel>,
+    _ctx: Arc<DeviceContext>,
+}
+
+unsafe impl Send for CompletionQueue {}
+unsafe impl Sync for CompletionQueue {}
+
+impl CompletionQueue {
+    /// Create a CQ of `size` entries, it is armed before anything can
+    /// complete on it when it has a channel.
+    pub fn new(
+        ctx: &Arc<DeviceContext>,
+        size: i32,
+        channel: Option<CompChannel>,
+    ) -> Result<Arc<Self>> {
+        let channel_ptr = channel
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |channel| channel.channel);
+        let cq = unsafe { ibv_create_cq(ctx.ctx, size, std::ptr::null_mut(), channel_ptr, 0) };
+        if cq.is_null() {
+            return Err(RdmaKvError::resource(
+                "creating CQ",
+                io::Error::last_os_error(),
+            ));
+        }
+        let cq = CompletionQueue {
+            cq,
+            channel,
+            _ctx: ctx.clone(),
+        };
+        if cq.channel.is_some() {
+            let err = unsafe { ibv_req_notify_cq(cq.cq, 0) };
+            if err != 0 {
+                return Err(RdmaKvError::resource(
+                    "arming CQ",
+                    io::Error::from_raw_os_error(err),
+                ));
+            }
+        }
+        Ok(Arc::new(cq))
+    }
+
+    /// Poll one completion, `None` when the CQ is empty.
+    pub fn poll(&self) -> Result<Option<ibv_wc>> {
+        let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
+        let poll_result = unsafe { ibv_poll_cq(self.cq, 1, &mut wc) };
+        if poll_result < 0 {
+            return Err(RdmaKvError::resource(
+                "polling CQ",
+                io::Error::from_raw_os_error(-poll_result),
+            ));
+        }
+        Ok((poll_result > 0).then_some(wc))
+    }
+
+    /// Fd of the completion channel, if there is one.
+    pub fn channel_fd(&self) -> Option<RawFd> {
+        self.channel.as_ref().map(CompChannel::fd)
+    }
+
+    /// Consume one event from the completion channel, ack it and arm the CQ
+    /// again. Returns false when no event is pending, or there is no channel.
+    pub fn get_event(&self) -> Result<bool> {
+        let Some(channel) = &self.channel else {
+            return Ok(false);
+        };
+        let mut ev_cq: *mut ibv_cq = std::ptr::null_mut();
+        let mut ev_ctx: *mut std::os::raw::c_void = std::ptr::null_mut();
+        if unsafe { ibv_get_cq_event(channel.channel, &mut ev_cq, &mut ev_ctx) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(false);
+            }
+            return Err(RdmaKvError::resource("reading CQ event", err));
+        }
+        unsafe { ibv_ack_cq_events(ev_cq, 1) };
+        let err = unsafe { ibv_req_notify_cq(ev_cq, 0) };
+        if err != 0 {
+            return Err(RdmaKvError::resource(
+                "arming CQ",
+                io::Error::from_raw_os_error(err),
+            ));
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for CompletionQueue {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_destroy_cq(self.cq) };
+        report_drop("destroying CQ", err);
+    }
+}
+
+/// An RC queue pair whose send and receive queues complete on the same CQ.
+pub struct QueuePair {
+    qp: *mut ibv_qp,
+    _pd: Arc<ProtectionDomain>,
+    _cq: Arc<CompletionQueue>,
+}
+
+unsafe impl Send for QueuePair {}
+unsafe impl Sync for QueuePair {}
+
+impl QueuePair {
+    /// Create a QP with `depth` entries on each queue and one sge per work
+    /// request, every send is signaled.
+    pub fn new(pd: &Arc<ProtectionDomain>, cq: &Arc<CompletionQueue>, depth: u32) -> Result<Self> {
+        let mut qp_init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        qp_init_attr.qp_type = ibv_qp_type::IBV_QPT_RC;
+        qp_init_attr.sq_sig_all = 1;
+        qp_init_attr.send_cq = cq.cq;
+        qp_init_attr.recv_cq = cq.cq;
+        qp_init_attr.cap.max_send_wr = depth;
+        qp_init_attr.cap.max_recv_wr = depth;
+        qp_init_attr.cap.max_send_sge = 1;
+        qp_init_attr.cap.max_recv_sge = 1;
+        let qp = unsafe { ibv_create_qp(pd.pd, &mut qp_init_attr) };
+        if qp.is_null() {
+            return Err(RdmaKvError::resource(
+                "creating QP",
+                io::Error::last_os_error(),
+            ));
+        }
+        debug!("QP was created, QP number={:#0X}", unsafe { (*qp).qp_num });
+        Ok(QueuePair {
+            qp,
+            _pd: pd.clone(),
+            _cq: cq.clone(),
+        })
+    }
+
+    pub fn qp_num(&self) -> u32 {
+        unsafe { (*self.qp).qp_num }
+    }
+
+    /// Apply `attr`, `context` names the transition for the error.
+    pub fn modify(
+        &self,
+        attr: &mut ibv_qp_attr,
+        mask: ibv_qp_attr_mask,
+        context: &'static str,
+    ) -> Result<()> {
+        let err = unsafe { ibv_modify_qp(self.qp, attr, mask.0 as _) };
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(RdmaKvError::resource(
+                context,
+                io::Error::from_raw_os_error(err),
+            ))
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The sges of `wr` must point into memory regions of this QP's PD that
+    /// stay registered until the request completes.
+    pub unsafe fn post_send(&self, wr: &mut ibv_send_wr) -> Result<()> {
+        let mut bad_wr: *mut ibv_send_wr = std::ptr::null_mut();
+        let err = ibv_post_send(self.qp, wr, &mut bad_wr);
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(RdmaKvError::resource(
+                "posting send",
+                io::Error::from_raw_os_error(err),
+            ))
+        }
+    }
+
+    /// # Safety
+    ///
+    /// Same as `post_send`.
+    pub unsafe fn post_recv(&self, wr: &mut ibv_recv_wr) -> Result<()> {
+        let mut bad_wr: *mut ibv_recv_wr = std::ptr::null_mut();
+        let err = ibv_post_recv(self.qp, wr, &mut bad_wr);
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(RdmaKvError::resource(
+                "posting receive",
+                io::Error::from_raw_os_error(err),
+            ))
+        }
+    }
+}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_destroy_qp(self.qp) };
+        report_drop("destroying QP", err);
+    }
+}
+
+/// Memory that stays where it is when its owner is moved, so that it can be
+/// registered.
+pub trait RegionBuf {
+    fn region(&self) -> (*const u8, usize);
+}
+
+impl RegionBuf for Vec<u8> {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr(), self.len())
+    }
+}
+
+// memory shared by the MRs of several PDs, which may all be accessed at once
+impl RegionBuf for Arc<[AtomicU8]> {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr() as _, self.len())
+    }
+}
+
+impl RegionBuf for Arc<[AtomicU64]> {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr() as _, std::mem::size_of_val(&**self))
+    }
+}
+
+/// A registered memory region that owns its memory, so that the memory
+/// cannot be freed while the HCA may still access it.
+pub struct MemoryRegion<B> {
+    mr: *mut ibv_mr,
+    buf: B,
+    _pd: Arc<ProtectionDomain>,
+}
+
+unsafe impl<B: Send> Send for MemoryRegion<B> {}
+unsafe impl<B: Sync> Sync for MemoryRegion<B> {}
+
+impl<B: RegionBuf> MemoryRegion<B> {
+    pub fn register(pd: &Arc<ProtectionDomain>, buf: B, access: ibv_access_flags) -> Result<Self> {
+        let (addr, len) = buf.region();
+        let mr = unsafe { ibv_reg_mr(pd.pd, addr as *mut _, len, access.0 as i32) };
+        if mr.is_null() {
+            return Err(RdmaKvError::resource(
+                "registering MR",
+                io::Error::last_os_error(),
+            ));
+        }
+        debug!("MR registered with addr={:p}", addr);
+        Ok(MemoryRegion {
+            mr,
+            buf,
+            _pd: pd.clone(),
+        })
+    }
+
+    /// Address of the registered memory.
+    pub fn addr(&self) -> u64 {
+        self.buf.region().0 as u64
+    }
+
+    pub fn lkey(&self) -> u32 {
+        unsafe { (*self.mr).lkey }
+    }
+
+    pub fn rkey(&self) -> u32 {
+        unsafe { (*self.mr).rkey }
+    }
+}
+
+impl<B> MemoryRegion<B> {
+    pub fn buf(&self) -> &B {
+        &self.buf
+    }
+}
+
+impl MemoryRegion<Vec<u8>> {
+    // a slice, not the Vec, a resize would move the registered memory
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl<B> Drop for MemoryRegion<B> {
+    fn drop(&mut self) {
+        let err = unsafe { ibv_dereg_mr(self.mr) };
+        report_drop("deregistering MR", err);
+    }
+}
+
+// teardown cannot fail the caller, a leak is only reported
+fn report_drop(what: &str, err: i32) {
+    if err != 0 {
+        warn!("{} failed: {}", what, io::Error::from_raw_os_error(err));
+    }
+}
@@ -0,0 +1,530 @@
+//! The write-ahead log of a `KvServer`, which makes its store outlive it.
+//!
+//! The file starts with `MAGIC` and a byte of flags, followed by a record
+//! per change: the length of its payload and the CRC-32 of the payload, both
+//! little endian, then the bincode payload. A record is appended before the
+//! change is applied and acknowledged. A crash can leave a record cut short
+//! at the end, it is cut off when the log is opened again. Once a snapshot
+//! holds what the first records did, they are dropped and the log is flagged
+//! as compacted: it is only replayed on top of that snapshot.
+use crate::cli::FsyncPolicy;
+use crate::engine::KvEngine;
+use crate::error::{RdmaKvError, Result};
+use crate::expiry::Expiry;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// "RKVWAL" and the version of the format.
+const MAGIC: [u8; 8] = *b"RKVWAL01";
+const HEADER_SIZE: usize = MAGIC.len() + 1;
+// the first records were dropped, a snapshot holds what they did
+const FLAG_COMPACTED: u8 = 1;
+// the payload length and its CRC
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// A change of the store. Expired keys are not logged, their deadline is.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Record<'a> {
+    /// Also keeps the key from expiring.
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+    Delete {
+        key: &'a str,
+    },
+    /// The key expires at `deadline`, see `expiry::now`.
+    Expire {
+        key: &'a str,
+        deadline: u64,
+    },
+    Persist {
+        key: &'a str,
+    },
+    /// A `Set` and an `Expire` at once.
+    SetExpiring {
+        key: &'a str,
+        value: &'a str,
+        deadline: u64,
+    },
+}
+
+impl Record<'_> {
+    fn apply(&self, engine: &mut dyn KvEngine, expiry: &mut Expiry) {
+        match *self {
+            Record::Set { key, value } => {
+                engine.set(key.to_string(), value.to_string());
+                expiry.clear(key);
+            }
+            Record::Delete { key } => {
+                engine.delete(key);
+                expiry.clear(key);
+            }
+            Record::Expire { key, deadline } => expiry.set(key, deadline),
+            Record::Persist { key } => {
+                expiry.clear(key);
+            }
+            Record::SetExpiring {
+                key,
+                value,
+                deadline,
+            } => {
+                engine.set(key.to_string(), value.to_string());
+                expiry.set(key, deadline);
+            }
+        }
+    }
+}
+
+/// An open log, appended to.
+pub struct Wal {
+    path: PathBuf,
+    policy: FsyncPolicy,
+    // bytes of whole records, what a failed append is cut back to
+    len: u64,
+    shared: Arc<Shared>,
+}
+
+// what the background syncer shares with the log
+struct Shared {
+    file: Mutex<File>,
+    // set by appends the syncer has not flushed yet
+    dirty: AtomicBool,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it when it is missing, and replay
+    /// its records into `engine` and `expiry`. A corrupt tail is cut off.
+    pub fn open(
+        path: &Path,
+        policy: FsyncPolicy,
+        engine: &mut dyn KvEngine,
+        expiry: &mut Expiry,
+    ) -> Result<Self> {
+        let err =
+            |context: &str, e| RdmaKvError::storage(format!("{} {}", context, path.display()), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| err("opening", e))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|e| err("reading", e))?;
+
+        let (len, records) = if bytes.len() < HEADER_SIZE
+            && MAGIC.starts_with(&bytes[..MAGIC.len().min(bytes.len())])
+        {
+            // new, or the crash came before the header was written
+            file.set_len(0).map_err(|e| err("truncating", e))?;
+            file.write_all(&header(0)).map_err(|e| err("writing", e))?;
+            (HEADER_SIZE, 0)
+        } else if bytes[..MAGIC.len().min(bytes.len())] != MAGIC {
+            return Err(RdmaKvError::InvalidInput(format!(
+                "{} is not a write-ahead log",
+                path.display()
+            )));
+        } else {
+            replay(&bytes, engine, expiry)
+        };
+        if len < bytes.len() {
+            warn!(
+                "Cutting off {} bytes of corrupt records at the end of {}",
+                bytes.len() - len,
+                path.display()
+            );
+            file.set_len(len as u64).map_err(|e| err("truncating", e))?;
+        }
+        file.sync_all().map_err(|e| err("syncing", e))?;
+        info!("Replayed {} records from {}", records, path.display());
+
+        let shared = Arc::new(Shared {
+            file: Mutex::new(file),
+            dirty: AtomicBool::new(false),
+        });
+        if let FsyncPolicy::Every(interval) = policy {
+            spawn_syncer(shared.clone(), interval);
+        }
+        Ok(Wal {
+            path: path.to_path_buf(),
+            policy,
+            len: len as u64,
+            shared,
+        })
+    }
+
+    /// Whether the log at `path` was compacted, so that the snapshot holding
+    /// the records it dropped has to be restored before it is replayed. A
+    /// missing log was not.
+    pub fn compacted(path: &Path) -> Result<bool> {
+        let mut header = [0; HEADER_SIZE];
+        let res = File::open(path).and_then(|file| file.read_exact_at(&mut header, 0));
+        match res {
+            Ok(()) => {
+                Ok(header[..MAGIC.len()] == MAGIC && header[MAGIC.len()] & FLAG_COMPACTED != 0)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(RdmaKvError::storage(
+                format!("reading {}", path.display()),
+                e,
+            )),
+        }
+    }
+
+    /// Where the next record is appended, what `drop_before` takes.
+    pub fn position(&self) -> u64 {
+        self.len
+    }
+
+    /// Drop the records before `position`, once a snapshot holds what they
+    /// did. The rest is written to a new log, flagged as compacted, that
+    /// replaces this one.
+    pub fn drop_before(&mut self, position: u64) -> Result<()> {
+        let err = |e| RdmaKvError::storage(format!("compacting {}", self.path.display()), e);
+        let mut file = self.shared.file.lock().unwrap();
+        let mut bytes = header(FLAG_COMPACTED).to_vec();
+        bytes.resize(HEADER_SIZE + (self.len - position) as usize, 0);
+        file.read_exact_at(&mut bytes[HEADER_SIZE..], position)
+            .map_err(err)?;
+        // the new log is open before it replaces the old one, so appends
+        // never go to a file that is gone
+        let (new, tmp) = write_tmp(&self.path, &bytes).map_err(err)?;
+        fs::rename(&tmp, &self.path).map_err(err)?;
+        *file = new;
+        info!(
+            "Dropped {} bytes of records a snapshot holds from {}",
+            self.len - bytes.len() as u64,
+            self.path.display()
+        );
+        self.len = bytes.len() as u64;
+        sync_dir(&self.path).map_err(err)
+    }
+
+    /// Append `record`, flushed as the policy says.
+    pub fn append(&mut self, record: &Record) -> Result<()> {
+        let payload = bincode::serialize(record)?;
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        let mut file = self.shared.file.lock().unwrap();
+        let res = file.write_all(&bytes).and_then(|()| match self.policy {
+            FsyncPolicy::Always => file.sync_data(),
+            FsyncPolicy::Every(_) => {
+                self.shared.dirty.store(true, Ordering::Release);
+                Ok(())
+            }
+            FsyncPolicy::Never => Ok(()),
+        });
+        if let Err(e) = res {
+            // the records after a torn one would be lost on replay
+            if let Err(e) = file.set_len(self.len) {
+                error!("Cutting {} back failed: {}", self.path.display(), e);
+            }
+            return Err(RdmaKvError::storage(
+                format!("appending to {}", self.path.display()),
+                e,
+            ));
+        }
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn header(flags: u8) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()] = flags;
+    header
+}
+
+// apply the records of bytes, which starts with the header, to engine and
+// expiry. Returns the length of the whole records and their number
+fn replay(bytes: &[u8], engine: &mut dyn KvEngine, expiry: &mut Expiry) -> (usize, usize) {
+    let mut pos = HEADER_SIZE;
+    let mut records = 0;
+    while let Some(header) = bytes.get(pos..pos + RECORD_HEADER_SIZE) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let start = pos + RECORD_HEADER_SIZE;
+        let Some(payload) = bytes.get(start..start + len) else {
+            break;
+        };
+        if crc32(payload) != crc {
+            break;
+        }
+        let Ok(record) = bincode::deserialize::<Record>(payload) else {
+            break;
+        };
+        record.apply(engine, expiry);
+        records += 1;
+        pos = start + len;
+    }
+    (pos, records)
+}
+
+// flush what was appended every interval, until the log is dropped
+fn spawn_syncer(shared: Arc<Shared>, interval: Duration) {
+    std::thread::spawn(move || {
+        while Arc::strong_count(&shared) > 1 {
+            std::thread::sleep(interval);
+            if shared.dirty.swap(false, Ordering::AcqRel) {
+                // appends go on while the copy is synced
+                let res = shared
+                    .file
+                    .lock()
+                    .unwrap()
+                    .try_clone()
+                    .and_then(|file| file.sync_data());
+                if let Err(e) = res {
+                    error!("Syncing the WAL failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Replace the file at `path` with `bytes`, through a temporary file that
+/// is renamed over it once it is on disk. A crash leaves the old file or
+/// the new one.
+pub fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let (_, tmp) = write_tmp(path, bytes)?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+// write bytes to a temporary file next to path, on disk, and return it open
+// for reading and appending along with its path
+fn write_tmp(path: &Path, bytes: &[u8]) -> io::Result<(File, PathBuf)> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&tmp)?;
+    file.set_len(0)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok((file, tmp))
+}
+
+// a rename of path has to reach the disk too
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// CRC-32 of IEEE 802.3, reflected.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::EngineKind;
+    use crate::engine;
+    use crate::temp_dir::TempDir;
+
+    fn open(path: &Path) -> (Wal, Box<dyn KvEngine>, Expiry) {
+        let mut engine = engine::new(EngineKind::Btree);
+        let mut expiry = Expiry::default();
+        let wal = Wal::open(path, FsyncPolicy::Always, engine.as_mut(), &mut expiry).unwrap();
+        (wal, engine, expiry)
+    }
+
+    fn keys(engine: &dyn KvEngine) -> Vec<String> {
+        let entries = engine.scan(std::ops::Bound::Unbounded, usize::MAX);
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let dir = TempDir::new("wal-replay");
+        let (mut wal, _, _) = open(&dir.join("wal"));
+        let records = [
+            Record::Set {
+                key: "a",
+                value: "1",
+            },
+            Record::SetExpiring {
+                key: "b",
+                value: "2",
+                deadline: 100,
+            },
+            Record::Set {
+                key: "c",
+                value: "3",
+            },
+            Record::Expire {
+                key: "c",
+                deadline: 200,
+            },
+            Record::Delete { key: "a" },
+            Record::Persist { key: "c" },
+        ];
+        for record in &records {
+            wal.append(record).unwrap();
+        }
+        let len = wal.position();
+        drop(wal);
+
+        assert!(!Wal::compacted(&dir.join("wal")).unwrap());
+        let (wal, engine, expiry) = open(&dir.join("wal"));
+        assert_eq!(wal.position(), len);
+        assert_eq!(fs::metadata(dir.join("wal")).unwrap().len(), len);
+        assert_eq!(keys(engine.as_ref()), ["b", "c"]);
+        assert_eq!(engine.get("b").as_deref(), Some("2"));
+        assert_eq!(engine.get("c").as_deref(), Some("3"));
+        assert_eq!(expiry.deadline("b"), Some(100));
+        assert_eq!(expiry.deadline("c"), None);
+    }
+
+    // what is done to the bytes of a log ending with a whole record
+    type Corruption = fn(&mut Vec<u8>);
+
+    #[test]
+    fn corrupt_tail_is_cut_off() {
+        let cases: [(&str, Corruption); 2] = [
+            ("truncated", |bytes| bytes.truncate(bytes.len() - 3)),
+            ("bit-flipped", |bytes| *bytes.last_mut().unwrap() ^= 0x10),
+        ];
+        for (name, corrupt) in cases {
+            let dir = TempDir::new(&format!("wal-{}", name));
+            let (mut wal, _, _) = open(&dir.join("wal"));
+            wal.append(&Record::Set {
+                key: "a",
+                value: "1",
+            })
+            .unwrap();
+            let whole = wal.position();
+            wal.append(&Record::Set {
+                key: "b",
+                value: "2",
+            })
+            .unwrap();
+            drop(wal);
+            let mut bytes = fs::read(dir.join("wal")).unwrap();
+            corrupt(&mut bytes);
+            fs::write(dir.join("wal"), &bytes).unwrap();
+
+            let (mut wal, engine, _) = open(&dir.join("wal"));
+            assert_eq!(keys(engine.as_ref()), ["a"], "{}", name);
+            assert_eq!(wal.position(), whole, "{}", name);
+            assert_eq!(
+                fs::metadata(dir.join("wal")).unwrap().len(),
+                whole,
+                "{}",
+                name
+            );
+            // what comes after the cut is replayed again
+            wal.append(&Record::Set {
+                key: "c",
+                value: "3",
+            })
+            .unwrap();
+            drop(wal);
+            let (_, engine, _) = open(&dir.join("wal"));
+            assert_eq!(keys(engine.as_ref()), ["a", "c"], "{}", name);
+        }
+    }
+
+    #[test]
+    fn not_a_wal() {
+        let dir = TempDir::new("wal-foreign");
+        fs::write(dir.join("wal"), b"something else entirely").unwrap();
+        let mut engine = engine::new(EngineKind::Btree);
+        let res = Wal::open(
+            &dir.join("wal"),
+            FsyncPolicy::Never,
+            engine.as_mut(),
+            &mut Expiry::default(),
+        );
+        assert!(matches!(res, Err(RdmaKvError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn drop_before_keeps_later_records() {
+        let dir = TempDir::new("wal-compact");
+        let (mut wal, _, _) = open(&dir.join("wal"));
+        wal.append(&Record::Set {
+            key: "a",
+            value: "1",
+        })
+        .unwrap();
+        wal.append(&Record::Set {
+            key: "b",
+            value: "2",
+        })
+        .unwrap();
+        let position = wal.position();
+        wal.append(&Record::SetExpiring {
+            key: "c",
+            value: "3",
+            deadline: 100,
+        })
+        .unwrap();
+        wal.append(&Record::Delete { key: "b" }).unwrap();
+        let tail = wal.position() - position;
+
+        assert!(!Wal::compacted(&dir.join("wal")).unwrap());
+        wal.drop_before(position).unwrap();
+        assert!(Wal::compacted(&dir.join("wal")).unwrap());
+        assert_eq!(wal.position(), HEADER_SIZE as u64 + tail);
+        // appends go to the new log
+        wal.append(&Record::Set {
+            key: "d",
+            value: "4",
+        })
+        .unwrap();
+        let len = wal.position();
+        drop(wal);
+        assert_eq!(fs::metadata(dir.join("wal")).unwrap().len(), len);
+
+        let (_, engine, expiry) = open(&dir.join("wal"));
+        assert_eq!(keys(engine.as_ref()), ["c", "d"]);
+        assert_eq!(expiry.deadline("c"), Some(100));
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7_be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+}